@@ -0,0 +1,104 @@
+//! Debug builds count heap allocations made by threads that promised not to allocate, so that
+//! accidental allocations on the audio thread don't go unnoticed.
+
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+thread_local! {
+    static FORBIDDEN: Cell<bool> = const { Cell::new(false) };
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Allocations that guarded code reported, over the whole run of the program
+static REPORTED_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(debug_assertions)]
+#[global_allocator]
+static ALLOCATOR: GuardedAllocator = GuardedAllocator;
+
+/// System allocator that counts allocations on threads that are currently guarded
+#[cfg(debug_assertions)]
+struct GuardedAllocator;
+
+#[cfg(debug_assertions)]
+unsafe impl std::alloc::GlobalAlloc for GuardedAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        record_allocation();
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: std::alloc::Layout) -> *mut u8 {
+        record_allocation();
+        std::alloc::System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+        record_allocation();
+        std::alloc::System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[cfg(debug_assertions)]
+fn record_allocation() {
+    // thread locals may already be gone while a thread shuts down
+    let _ = FORBIDDEN.try_with(|forbidden| {
+        if forbidden.get() {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        }
+    });
+}
+
+/// Marks the current thread as not allowed to allocate for as long as the guard lives.
+/// Allocations are only counted in debug builds, release builds always report none.
+pub struct AllocationGuard {
+    was_forbidden: bool,
+    allocations_before: usize,
+}
+
+impl AllocationGuard {
+    pub fn new() -> Self {
+        Self {
+            was_forbidden: FORBIDDEN.with(|forbidden| forbidden.replace(true)),
+            allocations_before: ALLOCATIONS.with(Cell::get),
+        }
+    }
+
+    /// Number of allocations the current thread made since the guard was created
+    pub fn allocations(&self) -> usize {
+        ALLOCATIONS.with(Cell::get) - self.allocations_before
+    }
+
+    /// Add the allocations made since the guard was created to [`reported_allocations`], for
+    /// code that must neither panic nor block, like the audio callback
+    pub fn report(&self) {
+        let allocations = self.allocations();
+        if allocations > 0 {
+            REPORTED_ALLOCATIONS.fetch_add(allocations, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Number of allocations that guarded code has reported so far
+pub fn reported_allocations() -> usize {
+    REPORTED_ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+/// Run `f` without counting its allocations, for code that allocates rarely and can't avoid it
+pub fn allow_allocations<R>(f: impl FnOnce() -> R) -> R {
+    let was_forbidden = FORBIDDEN.with(|forbidden| forbidden.replace(false));
+    let result = f();
+    FORBIDDEN.with(|forbidden| forbidden.set(was_forbidden));
+    result
+}
+
+impl Drop for AllocationGuard {
+    fn drop(&mut self) {
+        FORBIDDEN.with(|forbidden| forbidden.set(self.was_forbidden));
+    }
+}
@@ -1,39 +1,67 @@
-use std::sync::{
-    mpsc::{self, Sender},
-    Arc, Mutex,
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use eframe::{
-    egui::{
-        self, vec2, Align2, Color32, ComboBox, DragValue, FontId, Frame, Rect, RichText, Stroke, Ui,
-    },
-    emath::{Numeric, RectTransform},
-};
-use midly::{
-    num::{u4, u7},
-    MidiMessage,
+    egui::{self, vec2, Align2, ComboBox, DragValue, FontId, Frame, RichText, Stroke, Ui},
+    emath::Numeric,
 };
-use rodio::{OutputStream, OutputStreamHandle, Source};
+use midly::num::{u4, u7};
+use rayon::ThreadPool;
+use rodio::{OutputStream, OutputStreamHandle, PlayError, Source};
+#[cfg(unix)]
+use serde_json::Value;
 use strum::VariantArray;
 
 use crate::{
+    alloc_guard,
     audio_clip::AudioClip,
-    emitter::{Emitter, EmitterMessage},
-    midi::MidiConfig,
-    params::{ControlParam, EmitterParams, KeyMode},
+    emitter::{render_pool, replaced_params_channel, Emitter, EmitterMessage},
+    midi::{CcFeedback, ChannelMode, MidiConfig, MpeZone, ZoneSide},
+    osc::{OscServer, OscUpdate, DEFAULT_DIRT_SOUND},
+    params::{
+        CcMapping, ControlKind, ControlParam, ControlSource, DensityMode, EmitterParams,
+        ExpressionTarget, GrainCulling, JitterDistribution, KeyMode, NotePriority, ParamSync,
+        Parameter, ResponseCurve, SharedParams, VoiceMode, VoiceStealing, MAX_GRAINS,
+        MAX_POLYPHONY,
+    },
+    presets::{Preset, PresetBank, ProgramNumber},
+    router::{MidiLearnLink, MidiRouter, RouterUpdate},
+    settings::Settings,
+    smoothing::SmoothingMode,
+    tempo::{NoteDivision, NoteModifier, NoteValue, TempoSource},
     widgets::{
         envelope_plot::EnvelopePlot,
         parameter_knob::ParameterKnob,
         waveform::{GrainDrawData, Waveform, WaveformData},
     },
 };
+#[cfg(unix)]
+use crate::{
+    control::{default_socket_path, ControlCommand, ControlResult, ControlServer},
+    midi::VIRTUAL_PORT_NAME,
+};
 
 pub struct EmitterHandle {
     pub track_name: String,
     pub params: EmitterParams,
     pub waveform: Option<WaveformData>,
     pub grain_draw_data: Arc<Mutex<Vec<GrainDrawData>>>,
+    pub active_grains: Arc<AtomicUsize>,
+    pub active_notes: Arc<AtomicUsize>,
     pub msg_sender: Option<Sender<EmitterMessage>>,
+    /// sample the emitter is playing, kept for storing presets
+    pub clip: Option<AudioClip<f32>>,
+    /// Parameter that will be mapped to the next incoming MIDI CC
+    pub midi_learn: Option<ControlParam>,
+    /// Mapping that was just learned from a CC which may turn out to be the MSB of a 14-bit CC
+    pub learned_msb: Option<usize>,
 }
 
 impl Default for EmitterHandle {
@@ -43,21 +71,80 @@ impl Default for EmitterHandle {
             params: EmitterParams::default(),
             waveform: None,
             grain_draw_data: Arc::new(Mutex::new(Vec::new())),
+            active_grains: Arc::new(AtomicUsize::new(0)),
+            active_notes: Arc::new(AtomicUsize::new(0)),
             msg_sender: None,
+            clip: None,
+            midi_learn: None,
+            learned_msb: None,
         }
     }
 }
 
+impl EmitterHandle {
+    /// Send a message to the emitter that's playing
+    pub fn send(&self, msg: EmitterMessage) -> Result<(), &'static str> {
+        let sender = self.msg_sender.as_ref().ok_or("no sample is loaded")?;
+        sender.send(msg).map_err(|_| "the emitter has stopped")
+    }
+}
+
+/// SuperCollider's language port, which TidalCycles and many OSC tools send to by default
+const DEFAULT_OSC_PORT: u16 = 57120;
+
+/// How often to look for MIDI ports that came back
+const PORT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct NebulizerApp {
     stream: (OutputStream, OutputStreamHandle),
 
     midi_config: MidiConfig,
+    /// when the MIDI ports were last checked for inputs that can be reconnected
+    last_port_poll: Option<Instant>,
+    /// settings as they were last written to disk
+    saved_settings: Settings,
+    cc_feedback: CcFeedback,
+    midi_learn: MidiLearnLink,
+    learned_controls: Receiver<ControlSource>,
+    program_sender: Sender<ProgramNumber>,
+    program_changes: Receiver<ProgramNumber>,
 
-    midi_channel: Arc<Mutex<u4>>,
+    presets: PresetBank,
+    /// where the Store button puts the next preset
+    preset_slot: ProgramNumber,
+    /// whether stored presets switch to the current sample too
+    preset_sample: bool,
 
     active_panel: GuiPanel,
 
-    emitter: Arc<Mutex<EmitterHandle>>,
+    emitter: EmitterHandle,
+    /// values of continuous parameters shared with the audio thread
+    shared_params: Arc<SharedParams>,
+    param_sync: ParamSync,
+    /// settings that the emitter was last sent
+    sent_params: Option<EmitterParams>,
+
+    render_threads: usize,
+    /// kept here so that the pool is only ever dropped on the GUI thread
+    render_pool: Option<Arc<ThreadPool>>,
+    /// pools that were replaced, kept until the emitters have let go of them
+    retired_pools: Vec<Arc<ThreadPool>>,
+    /// settings that emitters replaced, sent back to be dropped here
+    replaced_params: Receiver<Box<EmitterParams>>,
+    replaced_params_sender: SyncSender<Box<EmitterParams>>,
+
+    osc_server: Option<OscServer>,
+    /// port that the OSC server listens on when started
+    osc_port: u16,
+    osc_error: Option<String>,
+    /// sound that `/dirt/play` events from TidalCycles have to name
+    dirt_sound: String,
+
+    /// socket that automation scripts send commands to
+    #[cfg(unix)]
+    control_server: Option<ControlServer>,
+    #[cfg(unix)]
+    control_error: Option<String>,
 
     theme: catppuccin_egui::Theme,
 }
@@ -67,13 +154,291 @@ impl NebulizerApp {
         // setup audio stream
         let (stream, stream_handle) = OutputStream::try_default().unwrap();
 
-        NebulizerApp {
+        let mut emitter = EmitterHandle::default();
+        let shared_params = Arc::new(SharedParams::new(&emitter.params));
+        let param_sync = ParamSync::new(&shared_params, &mut emitter.params);
+        let cc_feedback = CcFeedback::new(&emitter.params);
+
+        let (learn_tx, learn_rx) = mpsc::channel();
+        let (program_sender, program_changes) = mpsc::channel();
+        let (replaced_params_sender, replaced_params) = replaced_params_channel();
+
+        let saved_settings = Settings::load();
+        let mut midi_config = MidiConfig::new();
+        for (port_name, channel_mode) in &saved_settings.midi_inputs {
+            midi_config.remember(port_name, *channel_mode);
+        }
+
+        let osc_port = saved_settings.osc_port;
+        let dirt_sound = saved_settings.dirt_sound.clone();
+        let mut app = NebulizerApp {
             stream: (stream, stream_handle),
-            midi_config: MidiConfig::new(),
-            midi_channel: Arc::new(Mutex::new(u4::from(0))),
+            midi_config,
+            last_port_poll: None,
+            saved_settings,
+            cc_feedback,
+            midi_learn: MidiLearnLink {
+                active: Arc::new(AtomicBool::new(false)),
+                sender: learn_tx,
+            },
+            learned_controls: learn_rx,
+            program_sender,
+            program_changes,
+            presets: PresetBank::new(),
+            preset_slot: ProgramNumber::default(),
+            preset_sample: true,
             active_panel: GuiPanel::Main,
-            emitter: Arc::new(Mutex::new(EmitterHandle::default())),
+            emitter,
+            shared_params,
+            param_sync,
+            sent_params: None,
+            render_threads: 1,
+            render_pool: None,
+            retired_pools: Vec::new(),
+            replaced_params,
+            replaced_params_sender,
+            osc_server: None,
+            osc_port: osc_port.unwrap_or(DEFAULT_OSC_PORT),
+            osc_error: None,
+            dirt_sound: dirt_sound.unwrap_or_else(|| DEFAULT_DIRT_SOUND.to_string()),
+            #[cfg(unix)]
+            control_server: None,
+            #[cfg(unix)]
+            control_error: None,
             theme: catppuccin_egui::LATTE,
+        };
+        if osc_port.is_some() {
+            app.start_osc_server();
+        }
+        #[cfg(unix)]
+        match ControlServer::start(&default_socket_path()) {
+            Ok(server) => app.control_server = Some(server),
+            Err(err) => {
+                let path = default_socket_path();
+                app.control_error = Some(format!("Can't listen on {}: {err}", path.display()));
+            }
+        }
+        app
+    }
+
+    fn start_osc_server(&mut self) {
+        match OscServer::start(self.osc_port, self.shared_params.clone()) {
+            Ok(server) => {
+                if let Some(sender) = &self.emitter.msg_sender {
+                    server.update(OscUpdate::Emitter(sender.clone()));
+                }
+                server.update(OscUpdate::DirtSound(self.dirt_sound.clone()));
+                self.osc_server = Some(server);
+                self.osc_error = None;
+            }
+            Err(err) => {
+                self.osc_error = Some(format!("Can't listen on port {}: {err}", self.osc_port));
+            }
+        }
+    }
+
+    /// Let the emitter and MIDI thread know about everything that changed during this frame
+    fn publish_changes(&mut self) {
+        let handle = &mut self.emitter;
+
+        let midi = &self.midi_config;
+        self.cc_feedback
+            .send_changes(&handle.params, midi.output.as_ref(), midi.feedback_channel);
+        self.param_sync.push(&self.shared_params, &handle.params);
+
+        if let Some(sender) = &handle.msg_sender {
+            let changed = match &self.sent_params {
+                Some(sent) => !sent.same_settings(&handle.params),
+                None => true,
+            };
+            if changed {
+                let _ = sender.send(EmitterMessage::Params(Box::new(handle.params.clone())));
+                self.sent_params = Some(handle.params.clone());
+            }
+        }
+
+        let learning = handle.midi_learn.is_some() || handle.learned_msb.is_some();
+        self.midi_learn.active.store(learning, Ordering::Relaxed);
+
+        let settings = self.settings();
+        if settings != self.saved_settings {
+            settings.save();
+            self.saved_settings = settings;
+        }
+    }
+
+    fn settings(&self) -> Settings {
+        Settings {
+            midi_inputs: self
+                .midi_config
+                .connections
+                .iter()
+                .map(|c| (c.port_name.clone(), c.channel_mode))
+                .collect(),
+            osc_port: self.osc_server.as_ref().map(|server| server.port),
+            dirt_sound: Some(self.dirt_sound.clone()).filter(|sound| sound != DEFAULT_DIRT_SOUND),
+        }
+    }
+
+    /// Play a sample with a new emitter, letting the previous one ring out
+    fn load_sample(&mut self, track_name: String, clip: AudioClip<f32>) -> Result<(), PlayError> {
+        let handle = &mut self.emitter;
+        if let Some(sender) = &handle.msg_sender {
+            let _ = sender.send(EmitterMessage::Retire);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let emitter: Emitter<f32> = Emitter::new(
+            &clip,
+            rx,
+            handle.grain_draw_data.clone(),
+            self.shared_params.clone(),
+            handle.active_grains.clone(),
+            handle.active_notes.clone(),
+            self.replaced_params_sender.clone(),
+        );
+        handle.track_name = track_name;
+        handle.waveform = Some(WaveformData::new(clip.clone()));
+        handle.clip = Some(clip);
+        for connection in &self.midi_config.connections {
+            connection.update_router(RouterUpdate::Emitter(tx.clone()));
+        }
+        if let Some(server) = &self.osc_server {
+            server.update(OscUpdate::Emitter(tx.clone()));
+        }
+        let _ = tx.send(EmitterMessage::RenderPool(self.render_pool.clone()));
+        handle.msg_sender = Some(tx);
+        // the new emitter still needs to be sent the current settings
+        self.sent_params = None;
+        self.stream.1.play_raw(emitter.convert_samples())
+    }
+
+    /// Switch to the preset stored under a program number, if there is one.
+    /// MIDI CC mappings stay the same across presets.
+    fn load_preset(&mut self, number: ProgramNumber) -> Result<(), String> {
+        let Some(preset) = self.presets.get(&number) else {
+            return Err(format!("no preset is stored under {number}"));
+        };
+
+        let params = &mut self.emitter.params;
+        let midi_cc_map = std::mem::take(&mut params.midi_cc_map);
+        *params = preset.params.clone();
+        params.midi_cc_map = midi_cc_map;
+
+        if let Some((track_name, clip)) = preset.sample.clone() {
+            self.load_sample(track_name, clip)
+                .map_err(|err| format!("can't play the preset's sample: {err}"))?;
+        }
+        Ok(())
+    }
+
+    /// Store the current settings under a program number, along with the sample if asked to
+    fn store_preset(&mut self, number: ProgramNumber, with_sample: bool) {
+        let handle = &self.emitter;
+        let sample = handle.clip.clone().filter(|_| with_sample);
+        let preset = Preset {
+            params: handle.params.clone(),
+            sample: sample.map(|clip| (handle.track_name.clone(), clip)),
+        };
+        self.presets.insert(number, preset);
+    }
+
+    /// Carry out a command from the control socket
+    #[cfg(unix)]
+    fn run_command(&mut self, command: &ControlCommand, ctx: &egui::Context) -> ControlResult {
+        let handle = &mut self.emitter;
+        match command {
+            ControlCommand::LoadSample { path } => {
+                let clip = AudioClip::<f32>::load_from_file(path.display().to_string())
+                    .ok_or_else(|| format!("can't read or decode {}", path.display()))?;
+                let track_name = path
+                    .file_name()
+                    .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+                self.load_sample(track_name, clip)
+                    .map_err(|err| format!("can't play {}: {err}", path.display()))?;
+            }
+            ControlCommand::NoteOn {
+                key,
+                velocity,
+                channel,
+            } => handle.send(EmitterMessage::NoteOn {
+                channel: u4::from(*channel),
+                key: u7::from(*key),
+                vel: u7::from(*velocity),
+            })?,
+            ControlCommand::NoteOff { key, channel } => handle.send(EmitterMessage::NoteOff {
+                channel: u4::from(*channel),
+                key: u7::from(*key),
+            })?,
+            ControlCommand::Set { param, value } => {
+                handle.params.control_mut(param).set_clamped(*value);
+                let value = handle.params.control(param).value();
+                return Ok(vec![("value".to_string(), Value::from(value))]);
+            }
+            ControlCommand::Get { param } => {
+                let value = handle.params.control(param).value();
+                return Ok(vec![("value".to_string(), Value::from(value))]);
+            }
+            ControlCommand::SavePreset { number, sample } => self.store_preset(*number, *sample),
+            ControlCommand::LoadPreset { number } => self.load_preset(*number)?,
+            ControlCommand::Status => {
+                let sample = match &handle.clip {
+                    Some(_) => Value::String(handle.track_name.clone()),
+                    None => Value::Null,
+                };
+                let notes = handle.active_notes.load(Ordering::Relaxed);
+                let grains = handle.active_grains.load(Ordering::Relaxed);
+                return Ok(vec![
+                    ("sample".to_string(), sample),
+                    ("notes".to_string(), Value::from(notes)),
+                    ("grains".to_string(), Value::from(grains)),
+                ]);
+            }
+            ControlCommand::Shutdown => ctx.send_viewport_cmd(egui::ViewportCommand::Close),
+        }
+        Ok(Vec::new())
+    }
+
+    /// Switch the emitter over to a pool with the chosen number of render threads
+    fn rebuild_render_pool(&mut self) {
+        let pool = render_pool(self.render_threads);
+        if let Some(old) = std::mem::replace(&mut self.render_pool, pool) {
+            // the emitter still uses the old pool until it handles the message
+            self.retired_pools.push(old);
+        }
+        if let Some(sender) = &self.emitter.msg_sender {
+            let _ = sender.send(EmitterMessage::RenderPool(self.render_pool.clone()));
+        }
+    }
+
+    /// Connect remembered MIDI inputs whose port has (re)appeared
+    fn reconnect_midi_inputs(&mut self) {
+        if self
+            .last_port_poll
+            .is_some_and(|poll| poll.elapsed() < PORT_POLL_INTERVAL)
+        {
+            return;
+        }
+        self.last_port_poll = Some(Instant::now());
+
+        self.midi_config.poll_ports();
+        for name in self.midi_config.waiting_inputs() {
+            #[cfg(unix)]
+            if name == VIRTUAL_PORT_NAME {
+                let (tx, mut router) = new_router(self);
+                self.midi_config.open_virtual_port(tx, move |stamp, event| {
+                    router.handle(stamp, event);
+                });
+                continue;
+            }
+
+            if let Some((name, port)) = self.midi_config.find_port(&name) {
+                let (tx, mut router) = new_router(self);
+                self.midi_config
+                    .connect(&name, &port, tx, move |stamp, event| {
+                        router.handle(stamp, event);
+                    });
+            }
         }
     }
 }
@@ -87,6 +452,33 @@ impl eframe::App for NebulizerApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         catppuccin_egui::set_theme(ctx, self.theme);
 
+        while let Ok(source) = self.learned_controls.try_recv() {
+            midi_learn(&mut self.emitter, source);
+        }
+        // dropping the last reference to a pool stops its threads, which has to happen here
+        // rather than on the audio thread
+        self.retired_pools
+            .retain(|pool| Arc::strong_count(pool) > 1);
+        // the same goes for freeing settings
+        while self.replaced_params.try_recv().is_ok() {}
+        self.param_sync
+            .pull(&self.shared_params, &mut self.emitter.params);
+        // values moved by MIDI controllers don't need to be sent back to them, but everything
+        // changed from here on does
+        self.cc_feedback.observe(&self.emitter.params);
+
+        while let Ok(program) = self.program_changes.try_recv() {
+            if let Err(err) = self.load_preset(program) {
+                eprintln!("Can't switch to program {program}: {err}");
+            }
+        }
+        #[cfg(unix)]
+        while let Some(request) = self.control_server.as_ref().and_then(|s| s.next_request()) {
+            let result = self.run_command(&request.command, ctx);
+            request.reply(result);
+        }
+        self.reconnect_midi_inputs();
+
         egui::TopBottomPanel::top("menu bar").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
                 if ui.button("Main").clicked() {
@@ -104,39 +496,33 @@ impl eframe::App for NebulizerApp {
             GuiPanel::Settings => settings_panel(self, ui),
         });
 
+        self.publish_changes();
+
         ctx.request_repaint();
     }
 }
 
 fn emitters_panel(app: &mut NebulizerApp, ui: &mut Ui) {
-    let mut handle = app.emitter.lock().unwrap();
-
     ui.horizontal(|ui| {
         if ui.button(RichText::new("🗁").size(14.0)).clicked() {
             if let Some(path) = rfd::FileDialog::new().pick_file() {
                 // attempt to load and decode audio file
                 if let Some(clip) = AudioClip::<f32>::load_from_file(path.display().to_string()) {
-                    // if overwriting existing emitter, terminate it first
-                    if let Some(sender) = &handle.msg_sender {
-                        let _ = sender.send(EmitterMessage::Terminate).unwrap();
+                    let track_name = path.file_name().unwrap().to_str().unwrap().to_string();
+                    if let Err(err) = app.load_sample(track_name, clip) {
+                        app.emitter.track_name = format!("Failed to play audio file: {err}");
                     }
-
-                    let (tx, rx) = mpsc::channel();
-                    let emitter: Emitter<f32> =
-                        Emitter::new(&clip, rx, handle.grain_draw_data.clone());
-                    handle.track_name = path.file_name().unwrap().to_str().unwrap().to_string();
-                    handle.waveform = Some(WaveformData::new(clip));
-                    handle.msg_sender = Some(tx);
-                    let _ = app.stream.1.play_raw(emitter.convert_samples());
                 } else {
-                    handle.track_name = "Failed to read/decode audio file!".to_string();
+                    app.emitter.track_name = "Failed to read/decode audio file!".to_string();
                 }
             }
         }
 
-        ui.label(&handle.track_name);
+        ui.label(&app.emitter.track_name);
     });
 
+    let handle = &mut app.emitter;
+
     ui.add_space(4.0);
 
     let playheads = match handle.params.key_mode {
@@ -177,8 +563,45 @@ fn emitters_panel(app: &mut NebulizerApp, ui: &mut Ui) {
     ui.add_space(4.0);
 
     ui.horizontal(|ui| {
-        ui.label("Polyphony");
-        ui.add(DragValue::new(&mut handle.params.polyphony).clamp_range(1..=64));
+        ui.selectable_value(&mut handle.params.voice_mode, VoiceMode::Poly, "Poly");
+        ui.selectable_value(&mut handle.params.voice_mode, VoiceMode::Mono, "Mono");
+
+        match handle.params.voice_mode {
+            VoiceMode::Poly => {
+                ui.add(DragValue::new(&mut handle.params.polyphony).clamp_range(1..=MAX_POLYPHONY));
+
+                let stealing = &mut handle.params.voice_stealing;
+                ComboBox::from_id_source("voice-stealing")
+                    .width(96.0)
+                    .selected_text(stealing.to_string())
+                    .show_ui(ui, |ui| {
+                        for v in VoiceStealing::VARIANTS {
+                            ui.selectable_value(stealing, *v, v.to_string());
+                        }
+                    })
+                    .response
+                    .on_hover_text("Which note is stolen when polyphony is exceeded");
+
+                ui.checkbox(&mut handle.params.stolen_grains_ring_out, "Ring out")
+                    .on_hover_text("Let grains of stolen notes finish instead of fading them out");
+            }
+            VoiceMode::Mono => {
+                let priority = &mut handle.params.note_priority;
+                ComboBox::from_id_source("note-priority")
+                    .width(48.0)
+                    .selected_text(priority.to_string())
+                    .show_ui(ui, |ui| {
+                        for p in NotePriority::VARIANTS {
+                            ui.selectable_value(priority, *p, p.to_string());
+                        }
+                    });
+
+                ui.checkbox(&mut handle.params.legato, "Legato");
+
+                ui.label("Glide");
+                ui.add(milliseconds_drag_value(&mut handle.params.glide));
+            }
+        }
 
         ui.separator();
 
@@ -199,7 +622,7 @@ fn emitters_panel(app: &mut NebulizerApp, ui: &mut Ui) {
 
     ui.separator();
 
-    ui.columns(6, |cols| {
+    ui.columns(7, |cols| {
         cols[0].vertical_centered_justified(|ui| {
             ui.selectable_value(&mut handle.params.key_mode, KeyMode::Pitch, "Pitch");
             ui.selectable_value(&mut handle.params.key_mode, KeyMode::Slice, "Slice");
@@ -209,26 +632,121 @@ fn emitters_panel(app: &mut NebulizerApp, ui: &mut Ui) {
             KeyMode::Pitch => {
                 cols[1].add(
                     ParameterKnob::from_param(&mut handle.params.position)
+                        .midi_learn(
+                            ControlParam::Position,
+                            &mut handle.midi_learn,
+                            &mut handle.params.midi_cc_map,
+                        )
                         .max_decimals(2)
                         .label("Position"),
                 );
             }
             KeyMode::Slice => {
-                cols[1]
-                    .add(ParameterKnob::from_param(&mut handle.params.num_slices).label("Slices"));
+                cols[1].add(
+                    ParameterKnob::from_param(&mut handle.params.num_slices)
+                        .midi_learn(
+                            ControlParam::NumSlices,
+                            &mut handle.midi_learn,
+                            &mut handle.params.midi_cc_map,
+                        )
+                        .label("Slices"),
+                );
+            }
+        }
+        cols[2].add(
+            ParameterKnob::from_param(&mut handle.params.spray)
+                .midi_learn(
+                    ControlParam::Spray,
+                    &mut handle.midi_learn,
+                    &mut handle.params.midi_cc_map,
+                )
+                .label("Spray"),
+        );
+        if handle.params.length_synced {
+            note_division_combo(
+                &mut cols[3],
+                "length-division",
+                &mut handle.params.length_division,
+            );
+        } else {
+            cols[3].add(
+                ParameterKnob::from_param(&mut handle.params.length)
+                    .midi_learn(
+                        ControlParam::Length,
+                        &mut handle.midi_learn,
+                        &mut handle.params.midi_cc_map,
+                    )
+                    .label("Length"),
+            );
+        }
+        cols[3].horizontal(|ui| {
+            let synced = &mut handle.params.length_synced;
+            ui.selectable_value(synced, false, "ms");
+            ui.selectable_value(synced, true, "Sync")
+                .on_hover_text("Grain length as a note division of the tempo");
+        });
+        match handle.params.density_mode {
+            DensityMode::Free => {
+                cols[4].add(
+                    ParameterKnob::from_param(&mut handle.params.density)
+                        .midi_learn(
+                            ControlParam::Density,
+                            &mut handle.midi_learn,
+                            &mut handle.params.midi_cc_map,
+                        )
+                        .max_decimals(2)
+                        .label("Density")
+                        .suffix(" Hz"),
+                );
+            }
+            DensityMode::KeyTracked => {
+                cols[4].add(
+                    ParameterKnob::from_param(&mut handle.params.density_ratio)
+                        .midi_learn(
+                            ControlParam::DensityRatio,
+                            &mut handle.midi_learn,
+                            &mut handle.params.midi_cc_map,
+                        )
+                        .max_decimals(3)
+                        .label("Ratio")
+                        .suffix("×"),
+                );
+            }
+            DensityMode::Synced => {
+                note_division_combo(
+                    &mut cols[4],
+                    "density-division",
+                    &mut handle.params.density_division,
+                );
             }
         }
-        cols[2].add(ParameterKnob::from_param(&mut handle.params.spray).label("Spray"));
-        cols[3].add(ParameterKnob::from_param(&mut handle.params.length).label("Length"));
-        cols[4].add(
-            ParameterKnob::from_param(&mut handle.params.density)
+        cols[4].horizontal(|ui| {
+            let mode = &mut handle.params.density_mode;
+            ui.selectable_value(mode, DensityMode::Free, "Hz")
+                .on_hover_text("Play grains at a fixed rate");
+            ui.selectable_value(mode, DensityMode::KeyTracked, "Key")
+                .on_hover_text("Play grains at the frequency of each note");
+            ui.selectable_value(mode, DensityMode::Synced, "Sync")
+                .on_hover_text("Play grains at a note division of the tempo");
+        });
+        cols[5].add(
+            ParameterKnob::from_param(&mut handle.params.jitter)
+                .midi_learn(
+                    ControlParam::Jitter,
+                    &mut handle.midi_learn,
+                    &mut handle.params.midi_cc_map,
+                )
                 .max_decimals(2)
-                .label("Density")
-                .suffix(" Hz"),
+                .label("Jitter"),
         );
 
-        cols[5].add(
+        cols[6].add(
             ParameterKnob::from_param(&mut handle.params.amplitude)
+                .midi_learn(
+                    ControlParam::Amplitude,
+                    &mut handle.midi_learn,
+                    &mut handle.params.midi_cc_map,
+                )
                 .max_decimals(2)
                 .label("Level"),
         );
@@ -256,19 +774,39 @@ fn emitters_panel(app: &mut NebulizerApp, ui: &mut Ui) {
             ui.columns(4, |cols| {
                 cols[0].add(
                     ParameterKnob::from_param(&mut handle.params.note_envelope.attack)
+                        .midi_learn(
+                            ControlParam::NoteEnvelopeAttack,
+                            &mut handle.midi_learn,
+                            &mut handle.params.midi_cc_map,
+                        )
                         .label("Attack"),
                 );
                 cols[1].add(
                     ParameterKnob::from_param(&mut handle.params.note_envelope.decay)
+                        .midi_learn(
+                            ControlParam::NoteEnvelopeDecay,
+                            &mut handle.midi_learn,
+                            &mut handle.params.midi_cc_map,
+                        )
                         .label("Decay"),
                 );
                 cols[2].add(
                     ParameterKnob::from_param(&mut handle.params.note_envelope.sustain_level)
+                        .midi_learn(
+                            ControlParam::NoteEnvelopeSustain,
+                            &mut handle.midi_learn,
+                            &mut handle.params.midi_cc_map,
+                        )
                         .max_decimals(2)
                         .label("Sustain"),
                 );
                 cols[3].add(
                     ParameterKnob::from_param(&mut handle.params.note_envelope.release)
+                        .midi_learn(
+                            ControlParam::NoteEnvelopeRelease,
+                            &mut handle.midi_learn,
+                            &mut handle.params.midi_cc_map,
+                        )
                         .label("Release"),
                 );
             });
@@ -286,97 +824,394 @@ fn emitters_panel(app: &mut NebulizerApp, ui: &mut Ui) {
             ui.columns(2, |cols| {
                 cols[0].add(
                     ParameterKnob::from_param(&mut handle.params.grain_envelope.amount)
+                        .midi_learn(
+                            ControlParam::GrainEnvelopeAmount,
+                            &mut handle.midi_learn,
+                            &mut handle.params.midi_cc_map,
+                        )
                         .max_decimals(2)
                         .label("Amount"),
                 );
 
                 cols[1].add(
                     ParameterKnob::from_param(&mut handle.params.grain_envelope.skew)
+                        .midi_learn(
+                            ControlParam::GrainEnvelopeSkew,
+                            &mut handle.midi_learn,
+                            &mut handle.params.midi_cc_map,
+                        )
                         .max_decimals(2)
                         .label("Skew"),
                 );
             });
         });
     });
-
-    if let Some(sender) = &handle.msg_sender {
-        let _ = sender.send(EmitterMessage::Params(handle.params.clone()));
-    }
 }
 
 fn settings_panel(app: &mut NebulizerApp, ui: &mut Ui) {
-    match &app.midi_config.connection {
-        Some((name, _conn)) => {
-            let mut disconnect_clicked = false;
-            ui.label("MIDI Connection");
-            ui.horizontal(|ui| {
-                ui.label(name);
-                disconnect_clicked = ui.button("Disconnect").clicked();
-            });
-            if disconnect_clicked {
-                app.midi_config.connection = None;
+    ui.horizontal(|ui| {
+        ui.label("MIDI Inputs");
+        if ui.button("🔃").clicked() {
+            app.midi_config.refresh_ports();
+        }
+    });
+
+    let theme = app.theme;
+    let mut disconnected = None;
+    for (index, connection) in app.midi_config.connections.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            let (color, status) = if connection.is_connected() {
+                (theme.green, "Connected")
+            } else {
+                (theme.yellow, "Waiting for the port to come back")
+            };
+            ui.label(RichText::new("●").color(color))
+                .on_hover_text(status);
+
+            ui.label(&connection.port_name);
+            if ui.button("Disconnect").clicked() {
+                disconnected = Some(index);
             }
+        });
+
+        let previous_mode = connection.channel_mode;
+        channel_mode_settings(ui, index, &mut connection.channel_mode);
+        if connection.channel_mode != previous_mode {
+            connection.update_router(RouterUpdate::ChannelMode(connection.channel_mode));
         }
-        None => {
+    }
+    if let Some(index) = disconnected {
+        app.midi_config.connections.remove(index);
+    }
+
+    for (name, port) in app.midi_config.ports.clone().iter() {
+        if app.midi_config.is_remembered(name) {
+            continue;
+        }
+
+        ui.horizontal(|ui| {
+            ui.label(name);
+
+            if ui.button("Connect").clicked() {
+                let (tx, mut router) = new_router(app);
+                app.midi_config
+                    .connect(name, port, tx, move |stamp, event| {
+                        router.handle(stamp, event);
+                    });
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    {
+        if !app.midi_config.is_remembered(VIRTUAL_PORT_NAME) {
             ui.horizontal(|ui| {
-                ui.label("MIDI Connection");
-                if ui.button("🔃").clicked() {
-                    app.midi_config.refresh_ports();
+                ui.label(VIRTUAL_PORT_NAME);
+
+                let open = ui
+                    .button("Open")
+                    .on_hover_text("Let other programs send MIDI to nebulizer");
+                if open.clicked() {
+                    let (tx, mut router) = new_router(app);
+                    app.midi_config.open_virtual_port(tx, move |stamp, event| {
+                        router.handle(stamp, event);
+                    });
                 }
             });
+        }
+    }
 
-            for port in app.midi_config.ports.clone().iter() {
-                ui.horizontal(|ui| {
-                    ui.label(app.midi_config.midi_in.port_name(port).unwrap());
-
-                    if ui.button("Connect").clicked() {
-                        let handle = app.emitter.clone();
-                        let midi_channel = app.midi_channel.clone();
-                        app.midi_config.connect(port, move |channel, message| {
-                            if channel == *midi_channel.lock().unwrap() {
-                                handle_midi_msg(handle.clone(), message);
-                            }
-                        });
+    ui.separator();
+    ui.label("MIDI Output");
+    let midi = &mut app.midi_config;
+    let cc_feedback = &mut app.cc_feedback;
+    let previous_thru = midi.thru_output().map(|output| output.port_name);
+    ui.horizontal(|ui| {
+        let mut disconnect = false;
+        let mut chosen_port = None;
+        let selected = midi.output.as_ref().map(|output| output.port_name.clone());
+        ComboBox::from_id_source("midi-output")
+            .selected_text(selected.as_deref().unwrap_or("None"))
+            .show_ui(ui, |ui| {
+                disconnect = ui.selectable_label(selected.is_none(), "None").clicked();
+                for (index, (name, _)) in midi.output_ports.iter().enumerate() {
+                    let current = selected.as_ref() == Some(name);
+                    if ui.selectable_label(current, name).clicked() && !current {
+                        chosen_port = Some(index);
                     }
-                });
-            }
+                }
+            });
+        if disconnect {
+            midi.output = None;
+        }
+        if let Some(index) = chosen_port {
+            let (name, port) = midi.output_ports[index].clone();
+            midi.connect_output(&name, &port);
+            cc_feedback.forget();
         }
+
+        ui.checkbox(&mut midi.thru, "Thru")
+            .on_hover_text("Echo incoming notes to the output");
+
+        ui.separator();
+
+        ui.label("Feedback channel")
+            .on_hover_text("Mapped controllers are sent parameter changes on this channel");
+        let channel = &mut midi.feedback_channel;
+        ComboBox::from_id_source("feedback-channel")
+            .selected_text(channel.to_string())
+            .show_ui(ui, |ui| {
+                for i in 0..=15 {
+                    let chan = u4::from(i);
+                    ui.selectable_value(channel, chan, chan.to_string());
+                }
+            });
+    });
+    if midi.thru_output().map(|output| output.port_name) != previous_thru {
+        for connection in &midi.connections {
+            connection.update_router(RouterUpdate::Thru(midi.thru_output()));
+        }
+    }
+
+    if let Some(error) = &app.midi_config.error {
+        ui.colored_label(ui.visuals().error_fg_color, error);
+    }
+
+    let connections = &app.midi_config.connections;
+    if connections
+        .iter()
+        .any(|c| matches!(c.channel_mode, ChannelMode::Mpe(_)))
+    {
+        ui.separator();
+        ui.label("MPE");
+
+        let mpe = &mut app.emitter.params.mpe;
+        ui.horizontal(|ui| {
+            ui.label("Pitch bend");
+            ui.add(
+                DragValue::new(&mut mpe.pitch_bend_range)
+                    .clamp_range(1..=96)
+                    .suffix(" st"),
+            );
+        });
+        ui.horizontal(|ui| {
+            ui.label("Pressure");
+            expression_target_combo(ui, "mpe-pressure", &mut mpe.pressure_target);
+
+            ui.separator();
+
+            ui.label("Timbre");
+            expression_target_combo(ui, "mpe-timbre", &mut mpe.timbre_target);
+        });
     }
 
     ui.separator();
+    ui.label("Presets")
+        .on_hover_text("Switched with MIDI program changes and bank select");
+    ui.colored_label(
+        ui.visuals().warn_fg_color,
+        "Presets are only kept until nebulizer quits",
+    );
+    let mut store = false;
+    ui.horizontal(|ui| {
+        let slot = &mut app.preset_slot;
+        ui.label("Bank");
+        ui.add(DragValue::new(&mut slot.bank).clamp_range(0..=16383));
+        ui.label("Program");
+        ui.add(DragValue::new(&mut slot.program).clamp_range(0..=127));
 
-    ui.label("MIDI Channel");
-    let mut channel = app.midi_channel.lock().unwrap();
-    let mut selected_channel: u4 = channel.clone();
-    ComboBox::from_label("")
-        .selected_text(channel.to_string())
-        .show_ui(ui, |ui| {
-            for i in 0..=15 {
-                let chan = u4::from(i);
-                ui.selectable_value(&mut selected_channel, chan, chan.to_string());
+        ui.checkbox(&mut app.preset_sample, "With sample");
+
+        if ui.button("Store").clicked() {
+            store = true;
+        }
+    });
+    if store {
+        app.store_preset(app.preset_slot, app.preset_sample);
+    }
+    let mut load = None;
+    let mut delete = None;
+    for (number, preset) in &app.presets {
+        ui.horizontal(|ui| {
+            ui.label(number.to_string());
+            if let Some((track_name, _)) = &preset.sample {
+                ui.label(track_name);
+            }
+            if ui.button("Load").clicked() {
+                load = Some(*number);
+            }
+            if ui.button("🗑").clicked() {
+                delete = Some(*number);
             }
         });
-    *channel = selected_channel;
+    }
+    if let Some(number) = load {
+        if let Err(err) = app.load_preset(number) {
+            eprintln!("Can't load preset {number}: {err}");
+        }
+    }
+    if let Some(number) = delete {
+        app.presets.remove(&number);
+    }
+
+    ui.separator();
+    ui.label("OSC");
+    ui.horizontal(|ui| match &app.osc_server {
+        Some(server) => {
+            ui.label(format!("Listening on UDP port {}", server.port));
+            if ui.button("Stop").clicked() {
+                app.osc_server = None;
+            }
+        }
+        None => {
+            ui.label("UDP port");
+            ui.add(DragValue::new(&mut app.osc_port));
+            if ui.button("Listen").clicked() {
+                app.start_osc_server();
+            }
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.label("TidalCycles sound")
+            .on_hover_text("The s of /dirt/play events that the emitter plays");
+        let edit = egui::TextEdit::singleline(&mut app.dirt_sound).desired_width(120.0);
+        if ui.add(edit).changed() {
+            if let Some(server) = &app.osc_server {
+                server.update(OscUpdate::DirtSound(app.dirt_sound.clone()));
+            }
+        }
+    });
+    if let Some(error) = &app.osc_error {
+        ui.colored_label(ui.visuals().error_fg_color, error);
+    }
+
+    #[cfg(unix)]
+    {
+        ui.separator();
+        ui.label("Control socket")
+            .on_hover_text("Accepts JSON commands from scripts, one per line");
+        if let Some(server) = &app.control_server {
+            ui.label(format!("Listening on {}", server.path.display()));
+        }
+        if let Some(error) = &app.control_error {
+            ui.colored_label(ui.visuals().error_fg_color, error);
+        }
+    }
+
+    let handle = &mut app.emitter;
+
+    ui.separator();
+    ui.label("Parameter smoothing");
+    ui.horizontal(|ui| {
+        for m in SmoothingMode::VARIANTS {
+            ui.selectable_value(&mut handle.params.smoothing_mode, *m, m.to_string());
+        }
+
+        ui.separator();
+
+        ui.add(milliseconds_drag_value(&mut handle.params.smoothing));
+    });
+
+    ui.separator();
+    ui.label("Tempo");
+    ui.horizontal(|ui| {
+        for t in TempoSource::VARIANTS {
+            ui.selectable_value(&mut handle.params.tempo_source, *t, t.to_string());
+        }
+
+        ui.separator();
+
+        let internal = handle.params.tempo_source == TempoSource::Internal;
+        ui.add_enabled(
+            internal,
+            parameter_drag_value(&mut handle.params.bpm).suffix(" BPM"),
+        );
+
+        ui.separator();
+
+        ui.label("Swing");
+        ui.add(parameter_drag_value(&mut handle.params.swing).speed(0.01))
+            .on_hover_text("Delay every second grain when density is synced");
+    });
+    ui.checkbox(
+        &mut handle.params.stop_releases_notes,
+        "Release notes on MIDI Stop",
+    );
+
+    ui.separator();
+    ui.label("Grains");
+    ui.horizontal(|ui| {
+        ui.add(DragValue::new(&mut handle.params.max_grains).clamp_range(1..=MAX_GRAINS))
+            .on_hover_text("Maximum number of grains playing at once");
+
+        let culling = &mut handle.params.grain_culling;
+        ComboBox::from_id_source("grain-culling")
+            .selected_text(culling.to_string())
+            .show_ui(ui, |ui| {
+                for c in GrainCulling::VARIANTS {
+                    ui.selectable_value(culling, *c, c.to_string());
+                }
+            })
+            .response
+            .on_hover_text("What happens when a new grain would exceed the maximum");
+
+        ui.separator();
+
+        let active = handle.active_grains.load(Ordering::Relaxed);
+        ui.label(format!("{active} playing"));
+    });
+    ui.horizontal(|ui| {
+        ui.label("Jitter");
+        for d in JitterDistribution::VARIANTS {
+            ui.selectable_value(&mut handle.params.jitter_distribution, *d, d.to_string());
+        }
+    });
+    let mut rebuild_pool = false;
+    ui.horizontal(|ui| {
+        ui.label("Render threads");
+        let max_threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let response = ui
+            .add(DragValue::new(&mut app.render_threads).clamp_range(1..=max_threads))
+            .on_hover_text("Spread very dense grain clouds across several cores");
+        // starting threads for every value passed while dragging would be wasteful
+        let editing = response.dragged() || response.has_focus();
+        let edited = response.drag_stopped() || response.lost_focus();
+        let pool_threads = app
+            .render_pool
+            .as_ref()
+            .map_or(1, |p| p.current_num_threads());
+        rebuild_pool =
+            (edited || response.changed() && !editing) && app.render_threads != pool_threads;
+    });
+    let allocations = alloc_guard::reported_allocations();
+    if allocations > 0 {
+        let warning = format!("The audio thread allocated memory {allocations} times");
+        ui.colored_label(ui.visuals().warn_fg_color, warning)
+            .on_hover_text("Allocating can cause dropouts, please report this as a bug");
+    }
 
     ui.separator();
     ui.label("MIDI CC");
-    let mut handle = app.emitter.lock().unwrap();
     let mut to_delete = None;
-    for (e, (cc, param)) in handle.params.midi_cc_map.iter_mut().enumerate() {
+    for (e, mapping) in handle.params.midi_cc_map.iter_mut().enumerate() {
         ui.horizontal(|ui| {
-            ComboBox::from_id_source(format!("cc-{e}"))
-                .selected_text(format!("CC {}", cc))
+            let source = &mut mapping.source;
+            ComboBox::from_id_source(format!("kind-{e}"))
+                .selected_text(source.kind.to_string())
                 .show_ui(ui, |ui| {
-                    for i in 0u8..=127 {
-                        ui.selectable_value(cc, u7::from(i), format!("CC {}", i));
+                    for k in ControlKind::VARIANTS {
+                        ui.selectable_value(&mut source.kind, *k, k.to_string());
                     }
                 });
+            let numbers = source.kind.numbers();
+            source.number = source.number.clamp(*numbers.start(), *numbers.end());
+            ui.add(DragValue::new(&mut source.number).clamp_range(numbers));
 
             ComboBox::from_id_source(format!("param-{e}"))
-                .selected_text(param.to_string())
+                .selected_text(mapping.param.to_string())
                 .show_ui(ui, |ui| {
                     for p in ControlParam::VARIANTS {
-                        ui.selectable_value(param, p.clone(), p.to_string());
+                        ui.selectable_value(&mut mapping.param, p.clone(), p.to_string());
                     }
                 });
 
@@ -384,6 +1219,35 @@ fn settings_panel(app: &mut NebulizerApp, ui: &mut Ui) {
                 to_delete = Some(e);
             }
         });
+
+        ui.horizontal(|ui| {
+            ui.label("Range");
+            ui.add(
+                DragValue::new(&mut mapping.min)
+                    .clamp_range(0.0..=1.0)
+                    .speed(0.005)
+                    .max_decimals(2),
+            );
+            ui.label("to");
+            ui.add(
+                DragValue::new(&mut mapping.max)
+                    .clamp_range(0.0..=1.0)
+                    .speed(0.005)
+                    .max_decimals(2),
+            );
+
+            ui.checkbox(&mut mapping.invert, "Invert");
+
+            ComboBox::from_id_source(format!("curve-{e}"))
+                .selected_text(mapping.curve.to_string())
+                .show_ui(ui, |ui| {
+                    for c in ResponseCurve::VARIANTS {
+                        ui.selectable_value(&mut mapping.curve, *c, c.to_string());
+                    }
+                });
+        });
+
+        ui.add_space(4.0);
     }
 
     if let Some(idx) = to_delete {
@@ -391,78 +1255,147 @@ fn settings_panel(app: &mut NebulizerApp, ui: &mut Ui) {
     }
 
     if ui.button("➕").clicked() {
-        handle
-            .params
-            .midi_cc_map
-            .push((0.into(), ControlParam::Position));
+        handle.params.midi_cc_map.push(CcMapping::new(
+            ControlSource::new(ControlKind::Cc, 0),
+            ControlParam::Position,
+        ));
+    }
+    if rebuild_pool {
+        app.rebuild_render_pool();
     }
 }
 
-fn handle_midi_msg(emitter: Arc<Mutex<EmitterHandle>>, message: MidiMessage) {
-    let handle = &mut emitter.lock().unwrap();
-    if let Some(msg_sender) = &handle.msg_sender.clone() {
-        match message {
-            MidiMessage::NoteOn { key, vel } => {
-                let _ = msg_sender.send(EmitterMessage::NoteOn { key, vel });
-            }
-            MidiMessage::NoteOff { key, vel } => {
-                let _ = msg_sender.send(EmitterMessage::NoteOff { key, vel });
-            }
-            MidiMessage::Controller { controller, value } => {
-                let cc_map = handle.params.midi_cc_map.clone();
-                let norm_value = value.as_int() as f64 / 127.0;
-                for (cc, param) in cc_map.iter() {
-                    if *cc == controller {
-                        match param {
-                            ControlParam::Position => {
-                                handle.params.position.set_normalized(norm_value)
-                            }
-                            ControlParam::NumSlices => {
-                                handle.params.num_slices.set_normalized(norm_value)
-                            }
-                            ControlParam::Spray => handle.params.spray.set_normalized(norm_value),
-                            ControlParam::Length => handle.params.length.set_normalized(norm_value),
-                            ControlParam::Density => {
-                                handle.params.density.set_normalized(norm_value)
-                            }
-                            ControlParam::GrainEnvelopeAmount => handle
-                                .params
-                                .grain_envelope
-                                .amount
-                                .set_normalized(norm_value),
-                            ControlParam::GrainEnvelopeSkew => {
-                                handle.params.grain_envelope.skew.set_normalized(norm_value)
-                            }
-                            ControlParam::NoteEnvelopeAttack => handle
-                                .params
-                                .note_envelope
-                                .attack
-                                .set_normalized(norm_value),
-                            ControlParam::NoteEnvelopeDecay => {
-                                handle.params.note_envelope.decay.set_normalized(norm_value)
-                            }
-                            ControlParam::NoteEnvelopeSustain => handle
-                                .params
-                                .note_envelope
-                                .sustain_level
-                                .set_normalized(norm_value),
-                            ControlParam::NoteEnvelopeRelease => handle
-                                .params
-                                .note_envelope
-                                .release
-                                .set_normalized(norm_value),
-                            ControlParam::Transpose => {
-                                handle.params.transpose.set_normalized(norm_value)
-                            }
-                            ControlParam::Amplitude => {
-                                handle.params.amplitude.set_normalized(norm_value)
-                            }
+/// Router for a new MIDI connection, along with the sender that reaches it
+fn new_router(app: &NebulizerApp) -> (Sender<RouterUpdate>, MidiRouter) {
+    let (tx, rx) = mpsc::channel();
+    if let Some(sender) = &app.emitter.msg_sender {
+        let _ = tx.send(RouterUpdate::Emitter(sender.clone()));
+    }
+    let _ = tx.send(RouterUpdate::Thru(app.midi_config.thru_output()));
+    let router = MidiRouter::new(rx, app.midi_learn.clone(), app.program_sender.clone());
+    (tx, router)
+}
+
+/// Channel(s) that a MIDI input listens on, `index` tells apart the widgets of each input
+fn channel_mode_settings(ui: &mut Ui, index: usize, mode: &mut ChannelMode) {
+    ui.horizontal(|ui| {
+        let is_omni = matches!(*mode, ChannelMode::Omni);
+        let is_single = matches!(*mode, ChannelMode::Single(_));
+        let is_mpe = matches!(*mode, ChannelMode::Mpe(_));
+        if ui.selectable_label(is_omni, "Omni").clicked() {
+            *mode = ChannelMode::Omni;
+        }
+        if ui.selectable_label(is_single, "Single").clicked() && !is_single {
+            *mode = ChannelMode::Single(u4::from(0));
+        }
+        if ui.selectable_label(is_mpe, "MPE").clicked() && !is_mpe {
+            *mode = ChannelMode::Mpe(MpeZone::default());
+        }
+
+        match mode {
+            ChannelMode::Omni => {}
+            ChannelMode::Single(channel) => {
+                ui.separator();
+
+                ComboBox::from_id_source(("midi-channel", index))
+                    .selected_text(channel.to_string())
+                    .show_ui(ui, |ui| {
+                        for i in 0..=15 {
+                            let chan = u4::from(i);
+                            ui.selectable_value(channel, chan, chan.to_string());
                         }
-                    }
+                    });
+            }
+            ChannelMode::Mpe(zone) => {
+                ui.separator();
+
+                ui.selectable_value(&mut zone.side, ZoneSide::Lower, "Lower zone");
+                ui.selectable_value(&mut zone.side, ZoneSide::Upper, "Upper zone");
+
+                ui.separator();
+
+                ui.label("Members");
+                ui.add(DragValue::new(&mut zone.member_channels).clamp_range(1..=15));
+            }
+        }
+    });
+}
+
+/// Edit a duration parameter in whole milliseconds
+fn milliseconds_drag_value(param: &mut Parameter<Duration>) -> DragValue<'_> {
+    let range = param.range();
+    DragValue::from_get_set(|new_val| {
+        if let Some(v) = new_val {
+            param.set(Duration::from_secs_f64(v / 1000.0));
+        }
+        param.get().as_secs_f64() * 1000.0
+    })
+    .clamp_range(range.start().as_secs_f64() * 1000.0..=range.end().as_secs_f64() * 1000.0)
+    .max_decimals(0)
+    .suffix(" ms")
+}
+
+fn parameter_drag_value(param: &mut Parameter<f32>) -> DragValue<'_> {
+    let range = param.range();
+    DragValue::from_get_set(|new_val| {
+        if let Some(v) = new_val {
+            param.set(v as f32);
+        }
+        param.get() as f64
+    })
+    .clamp_range(*range.start()..=*range.end())
+    .max_decimals(2)
+}
+
+fn note_division_combo(ui: &mut Ui, id: &str, division: &mut NoteDivision) {
+    ComboBox::from_id_source(id)
+        .width(48.0)
+        .selected_text(division.to_string())
+        .show_ui(ui, |ui| {
+            for value in NoteValue::VARIANTS {
+                for modifier in NoteModifier::VARIANTS {
+                    let option = NoteDivision::new(*value, *modifier);
+                    ui.selectable_value(division, option, option.to_string());
                 }
-                let _ = msg_sender.send(EmitterMessage::Params(handle.params.clone()));
             }
-            _ => {}
+        });
+}
+
+fn expression_target_combo(ui: &mut Ui, id: &str, target: &mut ExpressionTarget) {
+    ComboBox::from_id_source(id)
+        .selected_text(target.to_string())
+        .show_ui(ui, |ui| {
+            for t in ExpressionTarget::VARIANTS {
+                ui.selectable_value(target, *t, t.to_string());
+            }
+        });
+}
+
+/// Map a controller to the parameter that is waiting for MIDI learn
+fn midi_learn(handle: &mut EmitterHandle, source: ControlSource) {
+    // a 14-bit CC sends its LSB right after the MSB that was just learned
+    if let Some(index) = handle.learned_msb.take() {
+        if let Some(mapping) = handle.params.midi_cc_map.get_mut(index) {
+            if source.kind == ControlKind::Cc14
+                && mapping.source == ControlSource::new(ControlKind::Cc, source.number)
+            {
+                mapping.source = source;
+            }
+        }
+    }
+
+    if let Some(param) = handle.midi_learn.take() {
+        let cc_map = &mut handle.params.midi_cc_map;
+        if !cc_map
+            .iter()
+            .any(|m| m.source == source && m.param == param)
+        {
+            cc_map.push(CcMapping::new(source, param));
+            if source.kind == ControlKind::Cc
+                && ControlKind::Cc14.numbers().contains(&source.number)
+            {
+                handle.learned_msb = Some(cc_map.len() - 1);
+            }
         }
     }
 }
@@ -17,8 +17,8 @@ where
     I: Sample + FromSample<i16>,
 {
     pub fn load_from_file(path: String) -> Option<Self> {
-        if let Some(file) = File::open(path).ok() {
-            if let Some(decoder) = Decoder::new(BufReader::new(file)).ok() {
+        if let Ok(file) = File::open(path) {
+            if let Ok(decoder) = Decoder::new(BufReader::new(file)) {
                 let channels = decoder.channels();
                 let sample_rate = decoder.sample_rate();
                 Some(AudioClip {
@@ -53,4 +53,9 @@ where
         let ns = NANOS_PER_SEC / (self.sample_rate as u64 * self.channels as u64);
         Duration::new(0, ns as u32)
     }
+
+    pub fn duration_per_frame(&self) -> Duration {
+        let ns = NANOS_PER_SEC / self.sample_rate as u64;
+        Duration::new(0, ns as u32)
+    }
 }
@@ -0,0 +1,119 @@
+//! Offline benchmark of grain rendering at its heaviest, run with `nebulizer --bench`
+
+use std::{
+    iter,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use midly::num::{u4, u7};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    audio_clip::AudioClip,
+    emitter::{render_pool, replaced_params_channel, Emitter, EmitterMessage, FrameEvent},
+    grain::CHANNELS,
+    params::{EmitterParams, SharedParams, MAX_GRAINS, MAX_POLYPHONY},
+};
+
+const SAMPLE_RATE: u32 = 48000;
+const BLOCK_FRAMES: usize = 256;
+/// Audio rendered before measuring, so that the cloud is fully built up
+const WARMUP: Duration = Duration::from_secs(1);
+/// Audio rendered while measuring
+const MEASURED: Duration = Duration::from_secs(2);
+
+pub fn run() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let clip = AudioClip {
+        data: (0..4 * SAMPLE_RATE as usize * CHANNELS)
+            .map(|_| rng.gen_range(-1.0..1.0))
+            .collect::<Vec<f32>>()
+            .into(),
+        channels: CHANNELS as u16,
+        sample_rate: SAMPLE_RATE,
+    };
+
+    let max_threads = thread::available_parallelism().map_or(1, |n| n.get());
+    println!(
+        "rendering {} s with {MAX_POLYPHONY} voices at maximum density and grain length",
+        MEASURED.as_secs_f32()
+    );
+
+    let mut threads = 1;
+    loop {
+        let (elapsed, grains, checksum) = render(&clip, threads);
+        println!(
+            "{threads:>3} threads: {grains:>5} grains, {:>8.3} s ({:>6.2}x realtime), checksum {checksum:.6}",
+            elapsed.as_secs_f32(),
+            MEASURED.as_secs_f32() / elapsed.as_secs_f32(),
+        );
+
+        if threads >= max_threads {
+            break;
+        }
+        threads = (threads * 2).min(max_threads);
+    }
+}
+
+/// Time it takes to render the measured audio, the number of playing grains and a checksum of the
+/// output, which should be the same for any number of threads
+fn render(clip: &AudioClip<f32>, threads: usize) -> (Duration, usize, f64) {
+    let mut params = EmitterParams::default();
+    params.density.set(*params.density.range().end());
+    params.length.set(*params.length.range().end());
+    params.polyphony = MAX_POLYPHONY;
+    params.max_grains = MAX_GRAINS;
+
+    let (tx, rx) = mpsc::channel();
+    // only ever one set of settings comes back
+    let (replaced_params, _replaced) = replaced_params_channel();
+    let grain_draw_data = Arc::new(Mutex::new(Vec::new()));
+    let shared_params = Arc::new(SharedParams::new(&params));
+    let active_grains = Arc::new(AtomicUsize::new(0));
+    let mut emitter = Emitter::new(
+        clip,
+        rx,
+        grain_draw_data,
+        shared_params,
+        active_grains.clone(),
+        Arc::new(AtomicUsize::new(0)),
+        replaced_params,
+    );
+
+    // the pool has to outlive the emitter's reference to it
+    let pool = render_pool(threads);
+    let _ = tx.send(EmitterMessage::RenderPool(pool.clone()));
+    let _ = tx.send(EmitterMessage::Params(Box::new(params)));
+
+    let notes = (0..MAX_POLYPHONY as u8).map(|i| FrameEvent {
+        offset: i as usize,
+        message: EmitterMessage::NoteOn {
+            channel: u4::from(0),
+            key: u7::from(36 + i),
+            vel: u7::from(100),
+        },
+    });
+
+    let mut block = [0.0; BLOCK_FRAMES * CHANNELS];
+    let blocks = |time: Duration| (time.as_secs_f32() * SAMPLE_RATE as f32) as usize / BLOCK_FRAMES;
+
+    emitter.process(&mut block, notes);
+    for _ in 0..blocks(WARMUP) {
+        emitter.process(&mut block, iter::empty());
+    }
+
+    let mut checksum = 0.0;
+    let start = Instant::now();
+    for _ in 0..blocks(MEASURED) {
+        emitter.process(&mut block, iter::empty());
+        checksum += block.iter().map(|s| s.abs() as f64).sum::<f64>();
+    }
+    let elapsed = start.elapsed();
+
+    (elapsed, active_grains.load(Ordering::Relaxed), checksum)
+}
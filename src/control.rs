@@ -0,0 +1,547 @@
+//! A Unix domain socket for automation scripts and tests, speaking newline-delimited JSON.
+//!
+//! Every line sent to the socket is a JSON object with a `"cmd"` and its arguments, and is
+//! answered by a line with `"ok": true` and the results, or `"ok": false` and an `"error"`.
+//! An `"id"` in a command is copied to its answer.
+//! - `{"cmd": "load_sample", "path": "/path/to/sample.wav"}`
+//! - `{"cmd": "note_on", "key": 60, "velocity": 100, "channel": 0}`, velocity and channel are
+//!   optional
+//! - `{"cmd": "note_off", "key": 60, "channel": 0}`
+//! - `{"cmd": "set", "param": "density", "value": 20.0}` answers with the value after clamping,
+//!   parameters are named as in OSC addresses
+//! - `{"cmd": "get", "param": "density"}` answers with `"value"`
+//! - `{"cmd": "save_preset", "bank": 0, "program": 1, "sample": true}` and
+//!   `{"cmd": "load_preset", "bank": 0, "program": 1}`, bank and sample are optional
+//! - `{"cmd": "status"}` answers with the `"sample"` that's loaded and the number of `"notes"`
+//!   and `"grains"` playing
+//! - `{"cmd": "shutdown"}` closes the app
+//!
+//! Commands are carried out by the GUI thread, which owns the emitter and the presets.
+
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use serde_json::{Map, Value};
+
+use crate::{params::ControlParam, presets::ProgramNumber};
+
+/// How long the server waits for a connection or a command before checking whether it should
+/// stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a client waits for the GUI thread to carry out a command
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest command line that is accepted, in bytes
+const MAX_LINE_LENGTH: usize = 1 << 20;
+
+pub enum ControlCommand {
+    LoadSample { path: PathBuf },
+    NoteOn { key: u8, velocity: u8, channel: u8 },
+    NoteOff { key: u8, channel: u8 },
+    Set { param: ControlParam, value: f64 },
+    Get { param: ControlParam },
+    SavePreset { number: ProgramNumber, sample: bool },
+    LoadPreset { number: ProgramNumber },
+    Status,
+    Shutdown,
+}
+
+impl ControlCommand {
+    fn parse(request: &Value) -> Result<ControlCommand, String> {
+        let Value::Object(_) = request else {
+            return Err("command isn't a JSON object".to_string());
+        };
+        let args = Args(request);
+        let command = match args.string("cmd")? {
+            "load_sample" => ControlCommand::LoadSample {
+                path: PathBuf::from(args.string("path")?),
+            },
+            "note_on" => ControlCommand::NoteOn {
+                key: args.integer("key", None, 127)? as u8,
+                velocity: args.integer("velocity", Some(100), 127)? as u8,
+                channel: args.integer("channel", Some(0), 15)? as u8,
+            },
+            "note_off" => ControlCommand::NoteOff {
+                key: args.integer("key", None, 127)? as u8,
+                channel: args.integer("channel", Some(0), 15)? as u8,
+            },
+            "set" => ControlCommand::Set {
+                param: args.param()?,
+                value: args.number("value")?,
+            },
+            "get" => ControlCommand::Get {
+                param: args.param()?,
+            },
+            "save_preset" => ControlCommand::SavePreset {
+                number: args.program_number()?,
+                sample: match request.get("sample") {
+                    None => true,
+                    Some(Value::Bool(sample)) => *sample,
+                    Some(_) => return Err("\"sample\" must be true or false".to_string()),
+                },
+            },
+            "load_preset" => ControlCommand::LoadPreset {
+                number: args.program_number()?,
+            },
+            "status" => ControlCommand::Status,
+            "shutdown" => ControlCommand::Shutdown,
+            cmd => return Err(format!("unknown command {cmd:?}")),
+        };
+        Ok(command)
+    }
+}
+
+/// Arguments of a command, checked as they're read
+struct Args<'a>(&'a Value);
+
+impl Args<'_> {
+    fn get(&self, name: &str) -> Result<&Value, String> {
+        self.0.get(name).ok_or_else(|| format!("missing {name:?}"))
+    }
+
+    fn string(&self, name: &str) -> Result<&str, String> {
+        let value = self.get(name)?;
+        value
+            .as_str()
+            .ok_or_else(|| format!("{name:?} must be a string"))
+    }
+
+    fn number(&self, name: &str) -> Result<f64, String> {
+        let value = self.get(name)?;
+        value
+            .as_f64()
+            .ok_or_else(|| format!("{name:?} must be a number"))
+    }
+
+    /// Whole number from 0 to `max`, using `default` when it's left out
+    fn integer(&self, name: &str, default: Option<u32>, max: u32) -> Result<u32, String> {
+        let value = match (self.0.get(name), default) {
+            (None, Some(default)) => return Ok(default),
+            _ => self.number(name)?,
+        };
+        if value.fract() != 0.0 || !(0.0..=max as f64).contains(&value) {
+            return Err(format!("{name:?} must be a whole number from 0 to {max}"));
+        }
+        Ok(value as u32)
+    }
+
+    fn param(&self) -> Result<ControlParam, String> {
+        let name = self.string("param")?;
+        ControlParam::from_snake_case_name(name)
+            .cloned()
+            .ok_or_else(|| format!("unknown parameter {name:?}"))
+    }
+
+    fn program_number(&self) -> Result<ProgramNumber, String> {
+        Ok(ProgramNumber {
+            bank: self.integer("bank", Some(0), 16383)? as u16,
+            program: self.integer("program", None, 127)? as u8,
+        })
+    }
+}
+
+/// Members that a successful command adds to its answer
+pub type ControlResult = Result<Vec<(String, Value)>, String>;
+
+/// A command waiting for the GUI thread to carry it out
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    reply: Sender<ControlResult>,
+}
+
+impl ControlRequest {
+    pub fn reply(self, result: ControlResult) {
+        // the client may have given up waiting
+        let _ = self.reply.send(result);
+    }
+}
+
+/// Where the socket is created unless told otherwise: in the user's runtime directory if there
+/// is one, so that other users can't reach it
+pub fn default_socket_path() -> PathBuf {
+    let dir = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir);
+    dir.join("nebulizer.sock")
+}
+
+/// Listens for connections on a Unix domain socket until dropped, and removes the socket then
+pub struct ControlServer {
+    pub path: PathBuf,
+    requests: Receiver<ControlRequest>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ControlServer {
+    pub fn start(path: &Path) -> io::Result<ControlServer> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "another instance is listening there",
+                ));
+            }
+            // left over from an instance that didn't shut down cleanly
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        // accepting doesn't have a timeout, so it's polled instead
+        listener.set_nonblocking(true)?;
+
+        let (tx, rx) = mpsc::channel();
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = thread::Builder::new().name("control".to_string()).spawn({
+            let running = running.clone();
+            move || accept_clients(listener, tx, running)
+        })?;
+
+        Ok(ControlServer {
+            path: path.to_path_buf(),
+            requests: rx,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    /// A command that has come in since the last call, if any
+    pub fn next_request(&self) -> Option<ControlRequest> {
+        self.requests.try_recv().ok()
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Serve every connection on its own thread, which ends along with the connection
+fn accept_clients(
+    listener: UnixListener,
+    requests: Sender<ControlRequest>,
+    running: Arc<AtomicBool>,
+) {
+    while running.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let requests = requests.clone();
+                let running = running.clone();
+                let client = thread::Builder::new()
+                    .name("control client".to_string())
+                    .spawn(move || {
+                        if let Err(err) = serve_client(stream, requests, running) {
+                            eprintln!("Control connection failed: {err}");
+                        }
+                    });
+                if let Err(err) = client {
+                    eprintln!("Can't serve control connection: {err}");
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(err) => {
+                eprintln!("Can't accept control connection: {err}");
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Answer the commands of one connection until it's closed
+fn serve_client(
+    stream: UnixStream,
+    requests: Sender<ControlRequest>,
+    running: Arc<AtomicBool>,
+) -> io::Result<()> {
+    // some platforms pass on the listener's non-blocking mode
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    // kept across timeouts, which can happen in the middle of a line
+    let mut line = Vec::new();
+    while running.load(Ordering::Relaxed) {
+        // reads no further than one byte past the limit, so a line without end can't fill memory
+        let remaining = (MAX_LINE_LENGTH + 1 - line.len()) as u64;
+        match (&mut reader).take(remaining).read_until(b'\n', &mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) if line.len() > MAX_LINE_LENGTH && !line.ends_with(b"\n") => {
+                let answer = error_answer(None, "command is too long".to_string());
+                writeln!(writer, "{answer}")?;
+                return Ok(());
+            }
+            Ok(_) => {
+                if !line.iter().all(u8::is_ascii_whitespace) {
+                    let answer = answer(&line, &requests);
+                    writeln!(writer, "{answer}")?;
+                }
+                line.clear();
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Carry out a command line and put together its answer
+fn answer(line: &[u8], requests: &Sender<ControlRequest>) -> Value {
+    let request: Value = match serde_json::from_slice(line) {
+        Ok(request) => request,
+        Err(err) => return error_answer(None, format!("invalid JSON: {err}")),
+    };
+    let id = request.get("id").cloned();
+
+    let result = ControlCommand::parse(&request).and_then(|command| {
+        let (tx, rx) = mpsc::channel();
+        requests
+            .send(ControlRequest { command, reply: tx })
+            .map_err(|_| "the app is shutting down".to_string())?;
+        rx.recv_timeout(REPLY_TIMEOUT)
+            .map_err(|_| "the app didn't answer in time".to_string())?
+    });
+
+    match result {
+        Ok(results) => {
+            let mut members = Map::new();
+            if let Some(id) = id {
+                members.insert("id".to_string(), id);
+            }
+            members.insert("ok".to_string(), Value::Bool(true));
+            members.extend(results);
+            Value::Object(members)
+        }
+        Err(err) => error_answer(id, err),
+    }
+}
+
+fn error_answer(id: Option<Value>, error: String) -> Value {
+    let mut members = Map::new();
+    if let Some(id) = id {
+        members.insert("id".to_string(), id);
+    }
+    members.insert("ok".to_string(), Value::Bool(false));
+    members.insert("error".to_string(), Value::String(error));
+    Value::Object(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<ControlCommand, String> {
+        ControlCommand::parse(&serde_json::from_str(text).unwrap())
+    }
+
+    /// Answer to a command line, where the GUI thread replies with `result`
+    fn answer_with(line: &[u8], result: ControlResult) -> String {
+        let (tx, rx) = mpsc::channel::<ControlRequest>();
+        let gui = thread::spawn(move || {
+            if let Ok(request) = rx.recv() {
+                request.reply(result);
+            }
+        });
+        let answer = answer(line, &tx);
+        drop(tx);
+        gui.join().unwrap();
+        answer.to_string()
+    }
+
+    #[test]
+    fn commands_are_parsed_with_their_defaults() {
+        assert!(matches!(
+            parse(r#"{"cmd": "note_on", "key": 60}"#),
+            Ok(ControlCommand::NoteOn {
+                key: 60,
+                velocity: 100,
+                channel: 0
+            })
+        ));
+        assert!(matches!(
+            parse(r#"{"cmd": "note_off", "key": 61, "channel": 3}"#),
+            Ok(ControlCommand::NoteOff {
+                key: 61,
+                channel: 3
+            })
+        ));
+        assert!(matches!(
+            parse(r#"{"cmd": "set", "param": "density", "value": 20.5}"#),
+            Ok(ControlCommand::Set { param: ControlParam::Density, value }) if value == 20.5
+        ));
+        assert!(matches!(
+            parse(r#"{"cmd": "save_preset", "program": 5}"#),
+            Ok(ControlCommand::SavePreset {
+                number: ProgramNumber {
+                    bank: 0,
+                    program: 5
+                },
+                sample: true
+            })
+        ));
+        assert!(matches!(
+            parse(r#"{"cmd": "load_preset", "bank": 300, "program": 127}"#),
+            Ok(ControlCommand::LoadPreset {
+                number: ProgramNumber {
+                    bank: 300,
+                    program: 127
+                }
+            })
+        ));
+    }
+
+    #[test]
+    fn strings_are_unescaped() {
+        let command = parse(r#"{"cmd": "load_sample", "path": "/a\"b\\c\né🎵"}"#);
+        let Ok(ControlCommand::LoadSample { path }) = command else {
+            panic!("not a load_sample command");
+        };
+        assert_eq!(path, PathBuf::from("/a\"b\\c\né🎵"));
+    }
+
+    #[test]
+    fn invalid_arguments_are_reported() {
+        let not_a_key = "\"key\" must be a whole number from 0 to 127";
+        for (text, error) in [
+            (r#"[1, 2]"#, "command isn't a JSON object"),
+            (r#"{"key": 60}"#, "missing \"cmd\""),
+            (r#"{"cmd": "dance"}"#, "unknown command \"dance\""),
+            (r#"{"cmd": "note_on", "key": 128}"#, not_a_key),
+            (r#"{"cmd": "note_on", "key": 60.5}"#, not_a_key),
+            (r#"{"cmd": "note_on", "key": -1}"#, not_a_key),
+            (
+                r#"{"cmd": "note_on", "key": "C4"}"#,
+                "\"key\" must be a number",
+            ),
+            (
+                r#"{"cmd": "get", "param": 3}"#,
+                "\"param\" must be a string",
+            ),
+            (
+                r#"{"cmd": "get", "param": "loudness"}"#,
+                "unknown parameter \"loudness\"",
+            ),
+            (
+                r#"{"cmd": "save_preset", "program": 1, "sample": 1}"#,
+                "\"sample\" must be true or false",
+            ),
+        ] {
+            assert_eq!(parse(text).err().as_deref(), Some(error), "{text}");
+        }
+    }
+
+    #[test]
+    fn answers_carry_the_id_and_results() {
+        let line = br#"{"cmd": "get", "param": "density", "id": "a"}"#;
+        let results = vec![("value".to_string(), Value::from(20.0))];
+        assert_eq!(
+            answer_with(line, Ok(results)),
+            r#"{"id":"a","ok":true,"value":20.0}"#
+        );
+
+        let line = br#"{"cmd": "load_preset", "program": 1, "id": 7}"#;
+        let error = "no preset is stored under 0:1".to_string();
+        assert_eq!(
+            answer_with(line, Err(error)),
+            r#"{"id":7,"ok":false,"error":"no preset is stored under 0:1"}"#
+        );
+
+        let line = br#"{"cmd": "dance", "id": [1]}"#;
+        assert_eq!(
+            answer_with(line, Ok(Vec::new())),
+            r#"{"id":[1],"ok":false,"error":"unknown command \"dance\""}"#
+        );
+    }
+
+    #[test]
+    fn invalid_json_is_answered_with_an_error() {
+        let deep = "[".repeat(1000);
+        let lines: [&[u8]; 6] = [
+            b"{\"cmd\": ",
+            b"{\"cmd\": \"status\"} x",
+            b"{\"cmd\": \"status\", }",
+            b"{\"cmd\": \"load_sample\", \"path\": \"\xff\"}",
+            br#"{"cmd": "set", "param": "density", "value": 1e999}"#,
+            deep.as_bytes(),
+        ];
+        for line in lines {
+            let answer: Value = serde_json::from_str(&answer_with(line, Ok(Vec::new()))).unwrap();
+            assert_eq!(answer["ok"], false, "{line:?}");
+            let error = answer["error"].as_str().unwrap();
+            assert!(error.starts_with("invalid JSON"), "{error}");
+        }
+    }
+
+    #[test]
+    fn server_answers_each_line() {
+        let path = env::temp_dir().join(format!("nebulizer-test-{}.sock", std::process::id()));
+        let server = ControlServer::start(&path).unwrap();
+
+        let client = thread::spawn({
+            let path = path.clone();
+            move || {
+                let mut stream = UnixStream::connect(path).unwrap();
+                stream
+                    .write_all(b"{\"cmd\": \"status\", \"id\": 1}\n\n{\"cmd\": 1}\n")
+                    .unwrap();
+                let mut answers = BufReader::new(stream).lines();
+                let mut next = || answers.next().unwrap().unwrap();
+                (next(), next())
+            }
+        });
+
+        // play the GUI thread until the client has its answers
+        while !client.is_finished() {
+            if let Some(request) = server.next_request() {
+                assert!(matches!(request.command, ControlCommand::Status));
+                request.reply(Ok(vec![("notes".to_string(), Value::from(2))]));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let (status, error) = client.join().unwrap();
+        assert_eq!(status, r#"{"id":1,"ok":true,"notes":2}"#);
+        assert_eq!(error, r#"{"ok":false,"error":"\"cmd\" must be a string"}"#);
+
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn server_rejects_lines_over_the_limit() {
+        let path = env::temp_dir().join(format!("nebulizer-test-{}-long.sock", std::process::id()));
+        let server = ControlServer::start(&path).unwrap();
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(&[b' '; MAX_LINE_LENGTH + 1]).unwrap();
+        let mut answers = BufReader::new(stream).lines();
+        assert_eq!(
+            answers.next().unwrap().unwrap(),
+            r#"{"ok":false,"error":"command is too long"}"#
+        );
+        // the connection is closed instead of waiting for the rest
+        assert!(answers.next().is_none());
+
+        drop(server);
+    }
+}
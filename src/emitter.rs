@@ -1,14 +1,52 @@
-use midly::num::u7;
-use rand::{thread_rng, Rng};
-use rodio::cpal::{FromSample, Sample as CpalSample};
+use eframe::egui::lerp;
+use midly::num::{u4, u7};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use rodio::cpal::FromSample;
 use rodio::{Sample, Source};
 use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
-use std::{mem, sync::mpsc::Receiver, time::Duration};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::{
+    iter, mem,
+    sync::mpsc::{self, Receiver, SyncSender},
+    time::Duration,
+};
 
-use crate::params::{EmitterParams, KeyMode};
+use crate::alloc_guard::{allow_allocations, AllocationGuard};
+use crate::midi::ControlValue;
+use crate::numeric::Numeric;
+use crate::params::{
+    DensityMode, EmitterParams, ExpressionTarget, GrainCulling, JitterDistribution, KeyMode,
+    MpeParams, NotePriority, ParamSync, Parameter, SharedParams, VoiceMode, VoiceStealing,
+    MAX_GRAINS, MAX_POLYPHONY,
+};
+use crate::smoothing::{smoothing_updates, Smoother};
+use crate::tempo::{swing_scale, ClockPosition, TempoSource, Transport, CLOCKS_PER_BEAT};
 use crate::widgets::waveform::GrainDrawData;
-use crate::{audio_clip::AudioClip, envelope::AdsrEnvelope, grain::Grain};
+use crate::{
+    audio_clip::AudioClip,
+    envelope::AdsrEnvelope,
+    grain::{Grain, CHANNELS},
+};
+
+/// How long grains of a stolen note, or grains culled to stay below the limit, take to fade out
+const STEAL_FADE: Duration = Duration::from_millis(10);
+
+/// Grains are rendered in groups of this size, each into its own buffer.  The buffers are summed
+/// in order, so the result doesn't depend on how the groups are spread across threads.
+const GRAINS_PER_TASK: usize = 64;
+
+/// Longest block that is rendered at once, longer buffers are split up
+const MAX_BLOCK_FRAMES: usize = 256;
+
+/// Every key on every MIDI channel could be held down at the same time
+const MAX_HELD_KEYS: usize = 16 * 128;
+
+/// Number of frames rendered at once when the emitter is played as a `Source`
+const SOURCE_BLOCK_FRAMES: usize = 64;
 
 #[derive(PartialEq)]
 enum NoteState {
@@ -18,28 +56,82 @@ enum NoteState {
 }
 
 struct Note {
+    /// unique id that grains use to refer back to the note that spawned them
+    id: u64,
+    channel: u4,
     key: u7,
+    /// how hard the key was struck, in range [0,1]
+    velocity: f32,
     envelope: AdsrEnvelope,
+    expression: NoteExpression,
 
     state: NoteState,
+    /// slide from a previous note's pitch and position in mono mode
+    glide: Option<Glide>,
 
-    since_last_grain: Duration,
+    /// settings of a note that releases itself
+    oneshot: Option<OneShot>,
+
+    /// time since the latest grain in the unit of `Emitter::grain_interval`, including how much
+    /// the grain was late by
+    since_last_grain: f64,
+    /// random factor applied to the time until the next grain
+    interval_scale: f32,
+    /// whether the latest grain was on the offbeat when swinging
+    offbeat: bool,
+}
+
+struct Glide {
+    /// fractional MIDI key the slide started from
+    from_pitch: f32,
+    /// slice position the slide started from
+    from_position: f32,
+    elapsed: Duration,
 }
 
 impl Note {
-    fn new(key: u7, envelope: AdsrEnvelope) -> Self {
+    fn new(
+        id: u64,
+        channel: u4,
+        key: u7,
+        velocity: f32,
+        envelope: AdsrEnvelope,
+        expression: NoteExpression,
+    ) -> Self {
         Self {
+            id,
+            channel,
             key,
+            velocity,
             envelope,
+            expression,
             state: NoteState::Held(Duration::ZERO),
-            since_last_grain: Duration::from_secs(100),
+            glide: None,
+            oneshot: None,
+            // so that the first grain plays right away
+            since_last_grain: f64::INFINITY,
+            interval_scale: 1.0,
+            // so that the first grain is on the beat
+            offbeat: true,
         }
     }
 
     fn update(&mut self, delta_time: Duration) {
-        self.since_last_grain += delta_time;
+        if let Some(glide) = &mut self.glide {
+            glide.elapsed += delta_time;
+        }
         match self.state {
-            NoteState::Held(time) => self.state = NoteState::Held(time + delta_time),
+            NoteState::Held(time) => {
+                let time = time + delta_time;
+                let envelope = &self.envelope;
+                let oneshot_length =
+                    envelope.attack.get() + envelope.decay.get() + envelope.release.get();
+                self.state = match self.oneshot.map(|oneshot| oneshot.length) {
+                    Some(Some(length)) if time >= length => NoteState::Released(Duration::ZERO),
+                    Some(None) if time >= oneshot_length => NoteState::Finished,
+                    _ => NoteState::Held(time),
+                };
+            }
             NoteState::Released(time) => {
                 let new_time = time + delta_time;
                 if new_time >= self.envelope.release.get() {
@@ -53,19 +145,127 @@ impl Note {
     }
 
     fn amplitude(&self) -> f32 {
-        match self.state {
+        let without_length = self.oneshot.is_some_and(|oneshot| oneshot.length.is_none());
+        let envelope = match self.state {
+            NoteState::Held(t) if without_length => self.envelope.oneshot_amplitude(t),
             NoteState::Held(t) => self.envelope.held_amplitude(t),
             NoteState::Released(t) => self.envelope.released_amplitude(t),
             NoteState::Finished => 0.0,
+        };
+        envelope * self.velocity
+    }
+}
+
+/// Per-note expression state of an MPE member channel
+#[derive(Clone, Copy, Default)]
+struct NoteExpression {
+    /// Pitch bend in range [-1,1]
+    pitch_bend: f32,
+    /// Channel pressure in range [0,1], if any was received
+    pressure: Option<f32>,
+    /// Timbre (CC 74) in range [0,1], if any was received
+    timbre: Option<f32>,
+}
+
+impl NoteExpression {
+    fn apply(&mut self, expression: Expression) {
+        match expression {
+            Expression::PitchBend(bend) => self.pitch_bend = bend,
+            Expression::Pressure(pressure) => self.pressure = Some(pressure),
+            Expression::Timbre(timbre) => self.timbre = Some(timbre),
         }
     }
+
+    /// Values of all expression dimensions that are assigned to the given target. With
+    /// `centered`, timbre rests at zero in the middle of its range, to shift a value both ways.
+    fn modulations(
+        &self,
+        params: &MpeParams,
+        target: ExpressionTarget,
+        centered: bool,
+    ) -> impl Iterator<Item = f32> {
+        let center = if centered { 0.5 } else { 0.0 };
+        [
+            (params.pressure_target, self.pressure),
+            (
+                params.timbre_target,
+                self.timbre.map(|timbre| timbre - center),
+            ),
+        ]
+        .into_iter()
+        .filter(move |(t, _)| *t == target)
+        .filter_map(|(_, value)| value)
+    }
+}
+
+pub enum Expression {
+    /// Pitch bend in range [-1,1]
+    PitchBend(f32),
+    /// Channel pressure in range [0,1]
+    Pressure(f32),
+    /// Timbre (CC 74) in range [0,1]
+    Timbre(f32),
+}
+
+/// Note that releases itself and brings some settings of its own, like an event from a
+/// TidalCycles pattern
+#[derive(Clone, Copy)]
+pub struct OneShot {
+    /// semitones from middle C, or the slice number in the Slice key mode
+    pub n: f32,
+    /// how long the note is held before it's released, or `None` to go through the envelope's
+    /// attack, decay and release right away
+    pub length: Option<Duration>,
+    /// factor for the playback speed of its grains
+    pub speed: f32,
+    pub gain: f32,
+    /// balance between the left (0) and right (1) channel
+    pub pan: f32,
+    /// part [0,1] of the clip that grains are taken from, instead of the position or slice
+    pub begin: Option<f32>,
+    pub end: Option<f32>,
+}
+
+/// Message that takes effect at a specific frame of a processed block
+pub struct FrameEvent {
+    /// frame within the block, counted from its start
+    pub offset: usize,
+    pub message: EmitterMessage,
 }
 
 pub enum EmitterMessage {
-    NoteOn { key: u7, vel: u7 },
-    NoteOff { key: u7, vel: u7 },
-    Params(EmitterParams),
-    Terminate,
+    /// A note on with a velocity of 0 is a note off
+    NoteOn {
+        channel: u4,
+        key: u7,
+        vel: u7,
+    },
+    NoteOff {
+        channel: u4,
+        key: u7,
+    },
+    OneShot(OneShot),
+    /// Per-note expression for all notes playing on an MPE member channel
+    Expression {
+        channel: u4,
+        expression: Expression,
+    },
+    /// Settings changed in the GUI (continuous values travel through `SharedParams` instead)
+    Params(Box<EmitterParams>),
+    /// Value from a MIDI controller, to be applied through the CC map
+    Control(ControlValue),
+    /// Tempo derived from the MIDI clock, in beats per minute
+    Tempo(f32),
+    /// Tick of the MIDI clock, which sends 24 per quarter note
+    ClockTick,
+    /// Master volume in range [0,1] from a universal SysEx message, which sets the amplitude
+    MasterVolume(f32),
+    Transport(Transport),
+    /// Worker threads that grains are rendered on, or `None` to render them on the audio thread.
+    /// The sender should keep its own reference so that the pool is never dropped by the emitter.
+    RenderPool(Option<Arc<ThreadPool>>),
+    /// Stop playing notes and terminate once the remaining grains have rung out
+    Retire,
 }
 
 pub struct Emitter<I>
@@ -73,16 +273,49 @@ where
     I: Sample,
 {
     audio_clip: AudioClip<I>,
-    current_audio_channel: u16,
+    /// last rendered block, handed out one sample at a time when used as a `Source`
+    block: [f32; SOURCE_BLOCK_FRAMES * CHANNELS],
+    /// index of the next sample in `block` to hand out
+    block_position: usize,
 
-    pub params: EmitterParams,
+    pub params: Box<EmitterParams>,
+    shared_params: Arc<SharedParams>,
+    param_sync: ParamSync,
+    /// replaced settings go back to the GUI thread, so that they're never freed in here
+    replaced_params: SyncSender<Box<EmitterParams>>,
+
+    /// continuous parameters as they glide towards their latest values
+    smoothed: SmoothedParams,
 
     msg_receiver: Receiver<EmitterMessage>,
     /// used to communicate the state of currently playing grains back to GUI
     grain_draw_data: Arc<Mutex<Vec<GrainDrawData>>>,
     notes: VecDeque<Note>,
-    grains: Vec<Grain<I>>,
+    next_note_id: u64,
+    grains: Vec<Grain>,
+    /// grains that aren't fading out, these count towards the grain limit
+    sounding_grains: usize,
+    /// number of playing grains, reported to the GUI
+    active_grains: Arc<AtomicUsize>,
+    /// number of playing notes, reported to the GUI
+    active_notes: Arc<AtomicUsize>,
+    rng: StdRng,
+    render_pool: Option<Arc<ThreadPool>>,
+    /// mix of each group of grains for the current block
+    partial_mixes: Vec<[f32; MAX_BLOCK_FRAMES * CHANNELS]>,
+    /// output gain of each frame of the current block
+    frame_gains: [f32; MAX_BLOCK_FRAMES],
+    /// frame of the current block that notes and messages are processed at
+    block_frame: usize,
+    /// most recent expression received on each MIDI channel
+    channel_expression: [NoteExpression; 16],
+    /// keys currently held down with their velocity, in the order they were pressed
+    held_keys: Vec<(u4, u7, u7)>,
+    /// position of the MIDI clock, which synced grains follow when it sets the tempo
+    clock: ClockPosition,
 
+    /// no new notes are played, and the emitter terminates when its last grain is done
+    retiring: bool,
     terminated: bool,
 }
 
@@ -94,142 +327,609 @@ where
         audio_clip: &AudioClip<I>,
         msg_receiver: Receiver<EmitterMessage>,
         grain_draw_data: Arc<Mutex<Vec<GrainDrawData>>>,
+        shared_params: Arc<SharedParams>,
+        active_grains: Arc<AtomicUsize>,
+        active_notes: Arc<AtomicUsize>,
+        replaced_params: SyncSender<Box<EmitterParams>>,
     ) -> Emitter<I>
     where
         I: Sample,
     {
+        let mut params = Box::<EmitterParams>::default();
+        let param_sync = ParamSync::new(&shared_params, &mut params);
+
+        // the audio thread only ever fills the draw data up to the grain pool size
+        grain_draw_data.lock().unwrap().reserve(MAX_GRAINS as usize);
+
         Emitter {
             audio_clip: audio_clip.clone(),
-            current_audio_channel: 0,
-            params: EmitterParams::default(),
+            block: [0.0; SOURCE_BLOCK_FRAMES * CHANNELS],
+            block_position: SOURCE_BLOCK_FRAMES * CHANNELS,
+            smoothed: SmoothedParams::new(&params),
+            params,
+            shared_params,
+            param_sync,
+            replaced_params,
 
             msg_receiver,
             grain_draw_data,
-            notes: VecDeque::new(),
-            grains: Vec::new(),
+            notes: VecDeque::with_capacity(MAX_POLYPHONY as usize),
+            next_note_id: 0,
+            grains: Vec::with_capacity(MAX_GRAINS as usize),
+            sounding_grains: 0,
+            active_grains,
+            active_notes,
+            rng: StdRng::from_entropy(),
+            render_pool: None,
+            partial_mixes: vec![
+                [0.0; MAX_BLOCK_FRAMES * CHANNELS];
+                MAX_GRAINS as usize / GRAINS_PER_TASK
+            ],
+            frame_gains: [0.0; MAX_BLOCK_FRAMES],
+            block_frame: 0,
+            channel_expression: [NoteExpression::default(); 16],
+            held_keys: Vec::with_capacity(MAX_HELD_KEYS),
+            clock: ClockPosition::new(audio_clip.sample_rate),
 
+            retiring: false,
             terminated: false,
         }
     }
 
-    fn make_grain(&self, audio_clip: &AudioClip<I>, note: &Note) -> Grain<I> {
-        let mut rng = thread_rng();
-
+    fn make_grain(&mut self, note: &Note) -> Grain {
         let start = {
-            let pos = match self.params.key_mode {
-                KeyMode::Pitch => self.params.position.get(),
+            let offset = self.expression_offset(note, ExpressionTarget::Position);
+            let begin = note.oneshot.and_then(|oneshot| oneshot.begin);
+            let pos = match (begin, &self.params.key_mode) {
+                (Some(begin), _) => begin,
 
-                KeyMode::Slice => {
-                    let num_slices = self.params.num_slices.get();
-                    let slice = note.key.as_int() % num_slices;
-                    slice as f32 / num_slices as f32
+                (None, KeyMode::Pitch) => {
+                    modulated(&self.params.position, self.smoothed.position.get(), offset)
+                }
+
+                (None, KeyMode::Slice) => {
+                    let num_slices = self.params.num_slices.get() as f32;
+                    (self.slice_position(note) + offset / num_slices).min(1.0)
                 }
             };
 
             if self.params.spray.get() > Duration::ZERO {
                 let spray_relative = {
                     let spray = self.params.spray.get().as_secs_f32();
-                    let clip = audio_clip.total_duration().as_secs_f32();
+                    let clip = self.audio_clip.total_duration().as_secs_f32();
                     spray / clip
                 };
                 let min = (pos - spray_relative / 2.0).max(0.0);
                 let max = (pos + spray_relative / 2.0).min(1.0);
-                rng.gen_range(min..max)
+                // a spray below the float resolution or at the clip's end leaves an empty range
+                if max > min {
+                    self.rng.gen_range(min..max)
+                } else {
+                    pos
+                }
             } else {
                 pos
             }
         };
 
+        let bend = note.expression.pitch_bend * self.params.mpe.pitch_bend_range as f32;
         let speed = match self.params.key_mode {
-            KeyMode::Pitch => {
-                interval_to_ratio((note.key.as_int() as i32 + self.params.transpose.get()) - 60)
-            }
-            KeyMode::Slice => interval_to_ratio(self.params.transpose.get()),
+            KeyMode::Pitch => interval_to_ratio(
+                self.note_pitch(note) + (self.params.transpose.get() - 60) as f32 + bend,
+            ),
+            KeyMode::Slice => interval_to_ratio(self.params.transpose.get() as f32 + bend),
         };
 
+        let oneshot = note.oneshot;
+        let speed = speed * oneshot.map_or(1.0, |oneshot| oneshot.speed);
+
+        let amplitude = note.amplitude()
+            * oneshot.map_or(1.0, |oneshot| oneshot.gain)
+            * note
+                .expression
+                .modulations(&self.params.mpe, ExpressionTarget::Amplitude, false)
+                .product::<f32>();
+
+        let length = if self.params.length_synced {
+            self.params.length_division.duration(self.params.bpm.get())
+        } else {
+            Duration::from_secs_f32(self.smoothed.length.get())
+        };
+        let length_offset = self.expression_offset(note, ExpressionTarget::Length);
+        let mut length = modulated(&self.params.length, length, length_offset);
+        if let Some(end) = oneshot.and_then(|oneshot| oneshot.end) {
+            // don't read past the end of the one-shot's part of the clip
+            let clip = self.audio_clip.total_duration();
+            length = length.min(clip.mul_f32((end - start).max(0.0)));
+        }
+
         Grain::new(
-            audio_clip.clone(),
+            note.id,
+            &self.audio_clip,
             start,
-            self.params.length.get(),
+            length,
             speed,
-            note.amplitude(),
+            amplitude,
             self.params.grain_envelope.clone(),
         )
+        .panned(oneshot.map_or(0.5, |oneshot| oneshot.pan))
+    }
+
+    /// Time from a note's latest grain to its next one in frames, which usually lands between
+    /// two frames. Synced grains that follow the MIDI clock count its ticks instead.
+    fn grain_interval(&self, note: &Note) -> f64 {
+        let offset = self.expression_offset(note, ExpressionTarget::Density);
+        let density = match self.params.density_mode {
+            DensityMode::Free => {
+                modulated(&self.params.density, self.smoothed.density.get(), offset)
+            }
+            DensityMode::KeyTracked => {
+                let ratio = &self.params.density_ratio;
+                let bend = note.expression.pitch_bend * self.params.mpe.pitch_bend_range as f32;
+                let frequency = 440.0 * interval_to_ratio(self.note_pitch(note) + bend - 69.0);
+                frequency * modulated(ratio, ratio.get(), offset)
+            }
+            DensityMode::Synced => {
+                let swing = swing_scale(self.params.swing.get(), note.offbeat);
+                let beats = self.params.density_division.beats() * swing * note.interval_scale;
+                if self.follows_clock() {
+                    return beats as f64 * CLOCKS_PER_BEAT as f64;
+                }
+                let seconds = beats as f64 * 60.0 / self.params.bpm.get() as f64;
+                return seconds * self.audio_clip.sample_rate as f64;
+            }
+        };
+        (note.interval_scale / density) as f64 * self.audio_clip.sample_rate as f64
+    }
+
+    /// Whether synced grains are timed by the ticks of the MIDI clock
+    fn follows_clock(&self) -> bool {
+        self.params.density_mode == DensityMode::Synced
+            && self.params.tempo_source == TempoSource::MidiClock
+    }
+
+    /// Random factor for the time until a note's next grain, averaging to 1
+    fn next_interval_scale(&mut self) -> f32 {
+        let jitter = self.params.jitter.get();
+        if jitter == 0.0 {
+            return 1.0;
+        }
+
+        let random = match self.params.jitter_distribution {
+            JitterDistribution::Uniform => self.rng.gen_range(0.0..2.0),
+            // exponentially distributed intervals make grain onsets a Poisson process
+            JitterDistribution::Poisson => -(1.0 - self.rng.gen::<f32>()).ln(),
+        };
+        lerp(1.0..=random, jitter)
+    }
+
+    /// Progress [0,1] of a note's slide towards its own key
+    fn glide_progress(&self, glide: &Glide) -> f32 {
+        let glide_time = self.params.glide.get();
+        if glide_time.is_zero() {
+            1.0
+        } else {
+            (glide.elapsed.as_secs_f32() / glide_time.as_secs_f32()).min(1.0)
+        }
+    }
+
+    /// Current (possibly fractional) MIDI key a note is playing at
+    fn note_pitch(&self, note: &Note) -> f32 {
+        let key = note.key.as_int() as f32;
+        match &note.glide {
+            Some(glide) => lerp(glide.from_pitch..=key, self.glide_progress(glide)),
+            None => key,
+        }
+    }
+
+    /// Current start position of a note's slice in the Slice key mode
+    fn slice_position(&self, note: &Note) -> f32 {
+        let num_slices = self.params.num_slices.get();
+        let slice = note.key.as_int() % num_slices;
+        let position = slice as f32 / num_slices as f32;
+        match &note.glide {
+            Some(glide) => lerp(glide.from_position..=position, self.glide_progress(glide)),
+            None => position,
+        }
+    }
+
+    fn new_note(&mut self, channel: u4, key: u7, vel: u7) -> Note {
+        let id = self.next_note_id;
+        self.next_note_id += 1;
+        Note::new(
+            id,
+            channel,
+            key,
+            vel.as_int() as f32 / 127.0,
+            self.params.note_envelope.clone(),
+            self.channel_expression[channel.as_int() as usize],
+        )
+    }
+
+    /// Index of the note that should make room for a new one
+    fn steal_candidate(&self, channel: u4, key: u7) -> Option<usize> {
+        // one-shots never take the place of the mono voice
+        let spared = match self.params.voice_mode {
+            VoiceMode::Poly => None,
+            VoiceMode::Mono => self.mono_voice(),
+        };
+        let mut candidates = self
+            .notes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| Some(*i) != spared);
+
+        let oldest = candidates.clone().next().map(|(i, _)| i);
+        match self.params.voice_stealing {
+            VoiceStealing::Oldest => oldest,
+            VoiceStealing::Quietest => candidates
+                .min_by(|(_, a), (_, b)| a.amplitude().total_cmp(&b.amplitude()))
+                .map(|(i, _)| i),
+            VoiceStealing::ReleasedFirst => candidates
+                .find(|(_, note)| matches!(note.state, NoteState::Released(_)))
+                .map(|(i, _)| i)
+                .or(oldest),
+            VoiceStealing::SameKey => candidates
+                .find(|(_, note)| note.channel == channel && note.key == key)
+                .map(|(i, _)| i)
+                .or(oldest),
+        }
+    }
+
+    /// Index of the note played by the mono voice, one-shots play next to it
+    fn mono_voice(&self) -> Option<usize> {
+        self.notes.iter().rposition(|note| note.oneshot.is_none())
+    }
+
+    /// Remove a note and fade out the grains it already spawned (unless they should ring out)
+    fn steal_note(&mut self, index: usize) {
+        if let Some(note) = self.notes.remove(index) {
+            if !self.params.stolen_grains_ring_out {
+                for grain in self.grains.iter_mut() {
+                    if grain.note_id == note.id && !grain.is_fading() {
+                        grain.fade_out(STEAL_FADE, self.block_frame);
+                        self.sounding_grains -= 1;
+                    }
+                }
+            }
+        }
     }
 
-    fn grain_interval(&self) -> Duration {
-        Duration::from_secs_f32(1.0 / self.params.density.get())
+    /// Held key that should be sounding in mono mode
+    fn priority_key(&self) -> Option<(u4, u7, u7)> {
+        match self.params.note_priority {
+            NotePriority::Last => self.held_keys.last(),
+            NotePriority::Low => self.held_keys.iter().min_by_key(|(_, key, _)| *key),
+            NotePriority::High => self.held_keys.iter().max_by_key(|(_, key, _)| *key),
+        }
+        .copied()
+    }
+
+    /// Steal notes while the polyphony is reached, to make room for a note on `key`
+    fn make_room_for_note(&mut self, channel: u4, key: u7) {
+        let polyphony = self.params.polyphony.min(MAX_POLYPHONY);
+        while polyphony < self.notes.len() as u32 + 1 {
+            match self.steal_candidate(channel, key) {
+                Some(index) => self.steal_note(index),
+                None => break,
+            }
+        }
+    }
+
+    /// Start a one-shot note next to the others, no matter the voice mode
+    fn play_oneshot(&mut self, mut oneshot: OneShot) {
+        let n = oneshot.n.floor();
+        let key = match self.params.key_mode {
+            KeyMode::Pitch => {
+                // keys are whole semitones, so the rest goes into the speed
+                oneshot.speed *= interval_to_ratio(oneshot.n - n);
+                60.0 + n
+            }
+            KeyMode::Slice => n,
+        };
+        let (channel, key) = (u4::from(0), u7::from(key.clamp(0.0, 127.0) as u8));
+        self.make_room_for_note(channel, key);
+
+        // the gain takes the place of the velocity
+        let mut note = self.new_note(channel, key, u7::max_value());
+        note.oneshot = Some(oneshot);
+        self.notes.push_back(note);
+    }
+
+    /// Move the single mono voice to a new key, starting a new note if nothing is sounding
+    fn play_mono(&mut self, channel: u4, key: u7, vel: u7) {
+        let expression = self.channel_expression[channel.as_int() as usize];
+
+        let index = match self.mono_voice() {
+            Some(index) if self.notes[index].state != NoteState::Finished => index,
+            _ => {
+                self.notes.retain(|note| note.oneshot.is_some());
+                let note = self.new_note(channel, key, vel);
+                self.notes.push_back(note);
+                return;
+            }
+        };
+
+        let voice = &self.notes[index];
+        let is_held = matches!(voice.state, NoteState::Held(_));
+        if is_held && voice.channel == channel && voice.key == key {
+            return;
+        }
+
+        let glide = if self.params.glide.get() > Duration::ZERO {
+            Some(Glide {
+                from_pitch: self.note_pitch(voice),
+                from_position: self.slice_position(voice),
+                elapsed: Duration::ZERO,
+            })
+        } else {
+            None
+        };
+
+        // without legato (or when the previous note was already let go) the envelope restarts
+        let retrigger = !(self.params.legato && is_held);
+        let envelope = self.params.note_envelope.clone();
+
+        let voice = &mut self.notes[index];
+        voice.channel = channel;
+        voice.key = key;
+        voice.expression = expression;
+        voice.glide = glide;
+        if retrigger {
+            voice.velocity = vel.as_int() as f32 / 127.0;
+            voice.envelope = envelope;
+            voice.state = NoteState::Held(Duration::ZERO);
+        }
+    }
+
+    /// Let every note play its next grain right away, on the beat
+    fn restart_grain_timing(&mut self) {
+        for note in self.notes.iter_mut() {
+            note.since_last_grain = f64::INFINITY;
+            note.offbeat = true;
+        }
+    }
+
+    /// Cull grains according to the culling policy while the grain limit is reached.
+    /// Returns whether there is room for a new grain.
+    fn make_room_for_grain(&mut self) -> bool {
+        if self.grains.len() >= MAX_GRAINS as usize {
+            return false;
+        }
+
+        while self.sounding_grains >= self.params.max_grains as usize {
+            let mut sounding = self.grains.iter_mut().filter(|grain| !grain.is_fading());
+            let culled = match self.params.grain_culling {
+                GrainCulling::SkipNew => None,
+                GrainCulling::FadeQuietest => {
+                    sounding.min_by(|a, b| a.current_gain().total_cmp(&b.current_gain()))
+                }
+                GrainCulling::FadeOldest => sounding.next(),
+            };
+
+            match culled {
+                Some(grain) => {
+                    grain.fade_out(STEAL_FADE, self.block_frame);
+                    self.sounding_grains -= 1;
+                }
+                None => return false,
+            }
+        }
+
+        true
+    }
+
+    /// Normalized amount by which a note's expression shifts a parameter
+    fn expression_offset(&self, note: &Note, target: ExpressionTarget) -> f32 {
+        note.expression
+            .modulations(&self.params.mpe, target, true)
+            .sum()
     }
 
     fn handle_message(&mut self, msg: EmitterMessage) {
         match msg {
-            EmitterMessage::NoteOn { key, .. } => {
-                while self.params.polyphony < self.notes.len() as u32 + 1 {
-                    self.notes.pop_front();
+            EmitterMessage::NoteOn { .. } | EmitterMessage::OneShot(_) if self.retiring => {}
+            EmitterMessage::NoteOn { channel, key, vel } if vel == 0 => {
+                self.handle_message(EmitterMessage::NoteOff { channel, key });
+            }
+            EmitterMessage::NoteOn { channel, key, vel } => {
+                self.held_keys.retain(|(held_channel, held_key, _)| {
+                    (*held_channel, *held_key) != (channel, key)
+                });
+                self.held_keys.push((channel, key, vel));
+
+                match self.params.voice_mode {
+                    VoiceMode::Poly => {
+                        // a retriggered key always takes over its own voice
+                        if self.params.voice_stealing == VoiceStealing::SameKey {
+                            if let Some(index) = self
+                                .notes
+                                .iter()
+                                .position(|note| note.channel == channel && note.key == key)
+                            {
+                                self.steal_note(index);
+                            }
+                        }
+
+                        self.make_room_for_note(channel, key);
+                        let note = self.new_note(channel, key, vel);
+                        self.notes.push_back(note);
+                    }
+                    VoiceMode::Mono => {
+                        if let Some((channel, key, vel)) = self.priority_key() {
+                            self.play_mono(channel, key, vel);
+                        }
+                    }
                 }
-                self.notes
-                    .push_back(Note::new(key, self.params.note_envelope.clone()));
             }
-            EmitterMessage::NoteOff { key, .. } => {
+            EmitterMessage::OneShot(oneshot) => self.play_oneshot(oneshot),
+            EmitterMessage::NoteOff { channel, key } => {
+                self.held_keys.retain(|(held_channel, held_key, _)| {
+                    (*held_channel, *held_key) != (channel, key)
+                });
+
+                if self.params.voice_mode == VoiceMode::Mono {
+                    // fall back to another key that is still held down
+                    if let Some((channel, key, vel)) = self.priority_key() {
+                        self.play_mono(channel, key, vel);
+                        return;
+                    }
+                }
+
+                // one-shots are released on their own
                 for note in self.notes.iter_mut() {
-                    if note.key == key {
+                    if note.oneshot.is_none() && note.channel == channel && note.key == key {
                         note.state = NoteState::Released(Duration::ZERO);
                     }
                 }
             }
-            EmitterMessage::Params(settings) => self.params = settings,
-            EmitterMessage::Terminate => {
-                self.terminated = true;
+            EmitterMessage::Expression {
+                channel,
+                expression,
+            } => {
+                let state = &mut self.channel_expression[channel.as_int() as usize];
+                state.apply(expression);
+                for note in self.notes.iter_mut() {
+                    if note.channel == channel {
+                        note.expression = *state;
+                    }
+                }
+            }
+            EmitterMessage::Params(settings) => {
+                let replaced = mem::replace(&mut self.params, settings);
+                // the GUI thread takes them back every frame before sending new ones, so there
+                // is always room
+                let _ = self.replaced_params.try_send(replaced);
+                // shared values may be newer than the ones that were sent along
+                self.param_sync
+                    .resync(&self.shared_params, &mut self.params);
+            }
+            EmitterMessage::Control(control) => {
+                for i in 0..self.params.midi_cc_map.len() {
+                    let mapping = &self.params.midi_cc_map[i];
+                    if mapping.responds_to(&control.source) {
+                        let (param, value) = (mapping.param.clone(), mapping.map(control.value));
+                        self.params.control_mut(&param).set_normalized(value);
+                    }
+                }
+                self.param_sync.push(&self.shared_params, &self.params);
+            }
+            EmitterMessage::Tempo(bpm) => {
+                if self.params.tempo_source == TempoSource::MidiClock {
+                    let range = self.params.bpm.range();
+                    self.params.bpm.set(bpm.clamp(*range.start(), *range.end()));
+                    self.param_sync.push(&self.shared_params, &self.params);
+                }
+            }
+            EmitterMessage::ClockTick => self.clock.tick(),
+            EmitterMessage::MasterVolume(volume) => {
+                self.params.amplitude.set(volume);
+                self.param_sync.push(&self.shared_params, &self.params);
+            }
+            EmitterMessage::Transport(transport) => match transport {
+                Transport::Start => self.restart_grain_timing(),
+                Transport::Stop => {
+                    if self.params.stop_releases_notes {
+                        self.held_keys.clear();
+                        for note in self.notes.iter_mut() {
+                            if let NoteState::Held(_) = note.state {
+                                note.state = NoteState::Released(Duration::ZERO);
+                            }
+                        }
+                        self.restart_grain_timing();
+                    }
+                }
+                Transport::Continue => {}
+            },
+            EmitterMessage::RenderPool(pool) => self.render_pool = pool,
+            EmitterMessage::Retire => {
+                self.retiring = true;
+                self.held_keys.clear();
+                self.notes.clear();
             }
         }
     }
 }
 
-impl<I> Iterator for Emitter<I>
+impl<I> Emitter<I>
 where
-    I: Default + Sample,
+    I: Sample + Send + Sync,
     f32: FromSample<I>,
 {
-    type Item = f32;
+    /// Render interleaved stereo frames into `out`, overwriting its contents.
+    /// `events` must be sorted by offset; events past the end of the block apply after its last
+    /// frame, as if they came at the start of the next block. Messages from the channel take
+    /// effect at the start of the block.
+    /// Runs on the audio thread, so nothing in here may allocate.
+    pub fn process(&mut self, out: &mut [f32], events: impl IntoIterator<Item = FrameEvent>) {
+        let guard = AllocationGuard::new();
 
-    #[inline]
-    fn next(&mut self) -> Option<Self::Item> {
+        self.block_frame = 0;
         while let Ok(msg) = self.msg_receiver.try_recv() {
             self.handle_message(msg);
         }
 
-        if self.terminated {
-            return None;
-        }
+        let mut events = events.into_iter().peekable();
+        for (i, block) in out.chunks_mut(MAX_BLOCK_FRAMES * CHANNELS).enumerate() {
+            let first_frame = i * MAX_BLOCK_FRAMES;
+            let frames = block.len() / CHANNELS;
 
-        // only update notes (and potentially create new grains) at the beginning of an interleaved
-        // sequence.  this prevents grains from being created with their channels out of sync
-        if self.current_audio_channel == 0 {
-            let notes = mem::take(&mut self.notes);
-            let mut live_notes = vec![];
-            for mut note in notes.into_iter() {
-                note.update(
-                    self.audio_clip
-                        .duration_per_sample()
-                        .mul_f32(self.audio_clip.channels as f32),
-                );
+            // notes and events go first, so that all grains of the block are known
+            for frame in 0..frames {
+                self.block_frame = frame;
+                while let Some(event) = events.next_if(|event| event.offset <= first_frame + frame)
+                {
+                    self.handle_message(event.message);
+                }
 
-                if note.state == NoteState::Finished {
-                    continue;
+                if self.terminated {
+                    self.frame_gains[frame] = 0.0;
+                } else {
+                    self.update_notes();
+                    self.frame_gains[frame] = self.smoothed.amplitude.get();
                 }
+            }
+
+            self.render_grains(frames);
 
-                if note.since_last_grain >= self.grain_interval() {
-                    let g = self.make_grain(&self.audio_clip, &note);
-                    self.grains.push(g);
-                    note.since_last_grain = Duration::ZERO;
+            let num_tasks = self.grains.len().div_ceil(GRAINS_PER_TASK);
+            block.fill(0.0);
+            for partial in self.partial_mixes[..num_tasks].iter() {
+                for (sample, mixed) in block.iter_mut().zip(partial) {
+                    *sample += mixed;
                 }
+            }
 
-                live_notes.push(note);
+            for (frame, gain) in block.chunks_exact_mut(CHANNELS).zip(self.frame_gains) {
+                for sample in frame.iter_mut() {
+                    // use tanh as a primitive limiter
+                    *sample = (*sample * gain).tanh();
+                }
             }
-            self.notes.extend(live_notes);
 
-            // only write new grain draw data when gui consumed the previous ones
-            let mut draw_grains = self.grain_draw_data.lock().unwrap();
+            self.grains.retain(|grain| !grain.is_finished());
+            if self.retiring && self.grains.is_empty() {
+                self.terminated = true;
+            }
+            self.sounding_grains = self.grains.iter().filter(|g| !g.is_fading()).count();
+            // the emitter that replaced this one reports its notes and grains from now on
+            if !self.retiring {
+                self.active_notes.store(self.notes.len(), Ordering::Relaxed);
+                self.active_grains
+                    .store(self.grains.len(), Ordering::Relaxed);
+            }
+        }
+
+        // the rest apply after the block, so fades they start begin with the next one
+        self.block_frame = 0;
+        for event in events {
+            self.handle_message(event.message);
+        }
+
+        // only write new grain draw data when gui consumed the previous ones, and never wait
+        // for the gui to let go of it. A retiring emitter shares it with its replacement.
+        let draw_data = (!self.retiring).then(|| self.grain_draw_data.try_lock().ok());
+        if let Some(mut draw_grains) = draw_data.flatten() {
             if draw_grains.is_empty() {
                 for grain in self.grains.iter() {
                     draw_grains.push(grain.draw());
@@ -237,31 +937,120 @@ where
             }
         }
 
-        let mut samples = vec![];
-        let mut live_grains = vec![];
-        for mut grain in self.grains.drain(..) {
-            if let Some(sample) = grain.next() {
-                live_grains.push(grain);
-                samples.push(sample);
+        guard.report();
+    }
+
+    /// Mix every group of grains into its own buffer, on the render pool if there is one
+    fn render_grains(&mut self, frames: usize) {
+        let audio_clip = &self.audio_clip;
+        let render =
+            |(grains, partial): (&mut [Grain], &mut [f32; MAX_BLOCK_FRAMES * CHANNELS])| {
+                let partial = &mut partial[..frames * CHANNELS];
+                partial.fill(0.0);
+                for grain in grains {
+                    grain.render_block(audio_clip, partial);
+                }
+            };
+
+        match &self.render_pool {
+            Some(pool) => {
+                let tasks = self
+                    .grains
+                    .par_chunks_mut(GRAINS_PER_TASK)
+                    .zip(self.partial_mixes.par_iter_mut());
+                // handing the work over to the pool may occasionally allocate
+                allow_allocations(|| pool.install(|| tasks.for_each(render)));
             }
+            None => self
+                .grains
+                .chunks_mut(GRAINS_PER_TASK)
+                .zip(self.partial_mixes.iter_mut())
+                .for_each(render),
         }
-        self.grains.extend(live_grains);
+    }
 
-        self.current_audio_channel = (self.current_audio_channel + 1) % self.channels();
+    /// Advance all notes by one frame and let them spawn their grains
+    fn update_notes(&mut self) {
+        self.param_sync.pull(&self.shared_params, &mut self.params);
+        self.smoothed
+            .update(&self.params, self.audio_clip.sample_rate);
 
-        if let Some(sample) = samples.into_iter().reduce(|a, b| a.saturating_add(b)) {
-            // use tanh as a primitive limiter
-            let sample = f32::from_sample(sample.amplify(self.params.amplitude.get())).tanh();
-            Some(sample.to_sample())
+        let frame_duration = self.audio_clip.duration_per_frame();
+        let sample_rate = self.audio_clip.sample_rate as f64;
+        let ticks_per_frame =
+            self.params.bpm.get() as f64 * CLOCKS_PER_BEAT as f64 / 60.0 / sample_rate;
+        let ticks = self.clock.advance(ticks_per_frame);
+        let (elapsed, frames_per_unit) = if self.follows_clock() {
+            (ticks, 1.0 / ticks_per_frame)
         } else {
-            Some(0.0)
+            (1.0, 1.0)
+        };
+
+        // take the notes out while they spawn grains, the deque keeps its capacity
+        let mut notes = mem::take(&mut self.notes);
+        notes.retain_mut(|note| {
+            note.update(frame_duration);
+            note.since_last_grain += elapsed;
+
+            if note.state == NoteState::Finished {
+                return false;
+            }
+
+            let interval = self.grain_interval(note);
+            if note.since_last_grain >= interval {
+                // the grain was due this long before the current frame
+                let late = note.since_last_grain - interval;
+                if self.make_room_for_grain() {
+                    let mut grain = self.make_grain(note).starting_at(self.block_frame);
+                    let late_frames = late * frames_per_unit;
+                    if late_frames < 1.0 {
+                        grain = grain.late_by(late_frames);
+                    }
+                    self.grains.push(grain);
+                    self.sounding_grains += 1;
+                }
+                // carry the lateness over, so that intervals aren't rounded up to whole frames,
+                // unless the note just started or a whole interval went by unnoticed
+                note.since_last_grain = if late < interval { late } else { 0.0 };
+                note.interval_scale = self.next_interval_scale();
+                note.offbeat = !note.offbeat;
+            }
+
+            true
+        });
+        self.notes = notes;
+    }
+}
+
+impl<I> Iterator for Emitter<I>
+where
+    I: Sample + Send + Sync,
+    f32: FromSample<I>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.block_position == self.block.len() {
+            let mut block = self.block;
+            self.process(&mut block, iter::empty());
+            self.block = block;
+            self.block_position = 0;
         }
+
+        if self.terminated {
+            return None;
+        }
+
+        let sample = self.block[self.block_position];
+        self.block_position += 1;
+        Some(sample)
     }
 }
 
 impl<I> Source for Emitter<I>
 where
-    I: Default + Sample,
+    I: Sample + Send + Sync,
     f32: FromSample<I>,
 {
     fn current_frame_len(&self) -> Option<usize> {
@@ -270,7 +1059,7 @@ where
 
     fn channels(&self) -> u16 {
         // hard code this for now, but it should probably be configurable
-        2
+        CHANNELS as u16
     }
 
     fn sample_rate(&self) -> u32 {
@@ -282,7 +1071,420 @@ where
     }
 }
 
+/// Worker threads for rendering grains, none when everything should stay on the audio thread
+pub fn render_pool(threads: usize) -> Option<Arc<ThreadPool>> {
+    if threads <= 1 {
+        return None;
+    }
+
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("grain-render-{i}"))
+        .build()
+        .ok()
+        .map(Arc::new)
+}
+
+/// Channel that emitters send replaced settings back through. The GUI thread empties it every
+/// frame and sends settings at most once a frame, so a few slots are plenty.
+pub fn replaced_params_channel() -> (SyncSender<Box<EmitterParams>>, Receiver<Box<EmitterParams>>) {
+    mpsc::sync_channel(4)
+}
+
 /// compute pitch ratio from number of semitones between notes
-fn interval_to_ratio(semitones: i32) -> f32 {
-    2.0_f32.powf(semitones as f32 / 12.0)
+fn interval_to_ratio(semitones: f32) -> f32 {
+    2.0_f32.powf(semitones / 12.0)
+}
+
+/// Value after shifting its normalized position within a parameter's range by some amount
+fn modulated<I>(param: &Parameter<I>, value: I, amount: f32) -> I
+where
+    I: Numeric,
+{
+    if amount == 0.0 {
+        return value;
+    }
+
+    let mut param = param.clone();
+    param.set(value);
+    param.set_normalized(param.get_normalized() + amount as f64);
+    param.get()
+}
+
+/// Continuous parameters that are smoothed in the audio thread
+struct SmoothedParams {
+    amplitude: Smoother,
+    position: Smoother,
+    density: Smoother,
+    /// grain length in seconds
+    length: Smoother,
+}
+
+impl SmoothedParams {
+    fn new(params: &EmitterParams) -> Self {
+        Self {
+            amplitude: Smoother::new(params.amplitude.get()),
+            position: Smoother::new(params.position.get()),
+            density: Smoother::new(params.density.get()),
+            length: Smoother::new(params.length.get().as_secs_f32()),
+        }
+    }
+
+    /// Advance all parameters by one frame towards their current values
+    fn update(&mut self, params: &EmitterParams, sample_rate: u32) {
+        let mode = params.smoothing_mode;
+        let frames = smoothing_updates(params.smoothing.get(), sample_rate);
+
+        self.amplitude.next(params.amplitude.get(), mode, frames);
+        self.position.next(params.position.get(), mode, frames);
+        self.density.next(params.density.get(), mode, frames);
+        self.length
+            .next(params.length.get().as_secs_f32(), mode, frames);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Sender};
+
+    use super::*;
+    use crate::params::{CcMapping, ControlKind, ControlParam, ControlSource};
+
+    const SAMPLE_RATE: u32 = 48000;
+    const BLOCK_FRAMES: usize = 256;
+
+    fn test_emitter() -> (Emitter<f32>, Sender<EmitterMessage>) {
+        let (replaced_params, _) = replaced_params_channel();
+        test_emitter_replacing(replaced_params)
+    }
+
+    /// Emitter that sends the settings it replaces to `replaced_params`
+    fn test_emitter_replacing(
+        replaced_params: SyncSender<Box<EmitterParams>>,
+    ) -> (Emitter<f32>, Sender<EmitterMessage>) {
+        let clip = AudioClip {
+            data: (0..SAMPLE_RATE as usize * CHANNELS)
+                .map(|i| (i as f32 * 0.01).sin())
+                .collect::<Vec<f32>>()
+                .into(),
+            channels: CHANNELS as u16,
+            sample_rate: SAMPLE_RATE,
+        };
+        let (tx, rx) = mpsc::channel();
+        let emitter = Emitter::new(
+            &clip,
+            rx,
+            Arc::new(Mutex::new(Vec::new())),
+            Arc::new(SharedParams::new(&EmitterParams::default())),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+            replaced_params,
+        );
+        (emitter, tx)
+    }
+
+    fn event(offset: usize, message: EmitterMessage) -> FrameEvent {
+        FrameEvent { offset, message }
+    }
+
+    fn note_on(key: u8) -> EmitterMessage {
+        EmitterMessage::NoteOn {
+            channel: u4::from(0),
+            key: u7::from(key),
+            vel: u7::from(100),
+        }
+    }
+
+    #[test]
+    fn processing_does_not_allocate() {
+        let (replaced_params, replaced) = replaced_params_channel();
+        let (mut emitter, tx) = test_emitter_replacing(replaced_params);
+
+        // few voices and grains, so that notes are stolen and grains culled all the time
+        let mut params = EmitterParams {
+            polyphony: 4,
+            max_grains: 16,
+            grain_culling: GrainCulling::FadeOldest,
+            ..Default::default()
+        };
+        params.density.set(*params.density.range().end());
+        let cc = ControlSource::new(ControlKind::Cc, 1);
+        params
+            .midi_cc_map
+            .push(CcMapping::new(cc, ControlParam::Position));
+
+        let mut out = [0.0; BLOCK_FRAMES * CHANNELS];
+        let mut max_notes = 0;
+        let mut max_grains = 0;
+        for round in 0..100 {
+            // messages and events are put together before the guard, as their senders would
+            tx.send(EmitterMessage::Params(Box::new(params.clone())))
+                .unwrap();
+            tx.send(EmitterMessage::Transport(Transport::Start))
+                .unwrap();
+            let mut events: Vec<_> = (0..8)
+                .map(|i| event(i * 16, note_on(36 + ((round * 8 + i) % 48) as u8)))
+                .collect();
+            events.push(event(
+                130,
+                EmitterMessage::NoteOff {
+                    channel: u4::from(0),
+                    key: u7::from(36 + ((round * 8) % 48) as u8),
+                },
+            ));
+            events.push(event(
+                140,
+                EmitterMessage::Control(ControlValue {
+                    source: cc,
+                    value: round as f64 / 100.0,
+                }),
+            ));
+            events.push(event(150, EmitterMessage::Params(Box::new(params.clone()))));
+            events.push(event(
+                160,
+                EmitterMessage::OneShot(OneShot {
+                    n: 1.5,
+                    length: None,
+                    speed: 1.0,
+                    gain: 1.0,
+                    pan: 0.25,
+                    begin: Some(0.1),
+                    end: Some(0.2),
+                }),
+            ));
+            // past the end of the block
+            events.push(event(BLOCK_FRAMES + 10, note_on(100)));
+
+            let guard = AllocationGuard::new();
+            emitter.process(&mut out, events);
+            assert_eq!(guard.allocations(), 0, "allocated in round {round}");
+            // both settings that were sent came back to be freed
+            assert_eq!(replaced.try_iter().count(), 2);
+
+            max_notes = max_notes.max(emitter.notes.len());
+            max_grains = max_grains.max(emitter.grains.len());
+        }
+
+        // the limits were actually reached
+        assert_eq!(max_notes, 4);
+        assert!(max_grains >= 16, "only {max_grains} grains played");
+    }
+
+    fn oneshot(n: f32) -> EmitterMessage {
+        EmitterMessage::OneShot(OneShot {
+            n,
+            length: None,
+            speed: 1.0,
+            gain: 1.0,
+            pan: 0.5,
+            begin: None,
+            end: None,
+        })
+    }
+
+    /// Keys of the mono voice and of the one-shots that are playing
+    fn mono_and_oneshot_keys(emitter: &Emitter<f32>) -> (Vec<u8>, Vec<u8>) {
+        let (oneshots, mono): (Vec<_>, Vec<_>) = emitter
+            .notes
+            .iter()
+            .partition(|note| note.oneshot.is_some());
+        let keys = |notes: Vec<&Note>| notes.iter().map(|note| note.key.as_int()).collect();
+        (keys(mono), keys(oneshots))
+    }
+
+    #[test]
+    fn resting_timbre_leaves_offsets_alone() {
+        let params = MpeParams {
+            pressure_target: ExpressionTarget::Position,
+            timbre_target: ExpressionTarget::Position,
+            ..Default::default()
+        };
+        let mut expression = NoteExpression::default();
+        expression.apply(Expression::Timbre(0.5));
+        let offset = |expression: &NoteExpression| {
+            expression
+                .modulations(&params, ExpressionTarget::Position, true)
+                .sum::<f32>()
+        };
+        assert_eq!(offset(&expression), 0.0);
+
+        // timbre shifts both ways, pressure only up from zero
+        expression.apply(Expression::Timbre(0.0));
+        assert_eq!(offset(&expression), -0.5);
+        expression.apply(Expression::Pressure(0.25));
+        assert_eq!(offset(&expression), -0.25);
+    }
+
+    #[test]
+    fn notes_start_at_their_frame() {
+        let (mut emitter, _tx) = test_emitter();
+        let mut out = [0.0; BLOCK_FRAMES * CHANNELS];
+        emitter.process(&mut out, [event(100, note_on(60))]);
+
+        let (before, after) = out.split_at(100 * CHANNELS);
+        assert!(before.iter().all(|sample| *sample == 0.0));
+        assert!(after.iter().any(|sample| *sample != 0.0));
+    }
+
+    #[test]
+    fn retiring_emitter_leaves_the_counts_to_its_replacement() {
+        let (mut emitter, tx) = test_emitter();
+        let mut out = [0.0; BLOCK_FRAMES * CHANNELS];
+        emitter.process(&mut out, [event(0, note_on(60))]);
+        assert!(emitter.active_grains.load(Ordering::Relaxed) > 0);
+
+        // the replacement has nothing playing yet
+        tx.send(EmitterMessage::Retire).unwrap();
+        emitter.active_grains.store(0, Ordering::Relaxed);
+        emitter.active_notes.store(0, Ordering::Relaxed);
+        emitter.grain_draw_data.lock().unwrap().clear();
+        emitter.process(&mut out, []);
+
+        assert!(!emitter.grains.is_empty(), "grains should still ring out");
+        assert_eq!(emitter.active_grains.load(Ordering::Relaxed), 0);
+        assert_eq!(emitter.active_notes.load(Ordering::Relaxed), 0);
+        assert!(emitter.grain_draw_data.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn tiny_spray_keeps_to_the_position() {
+        let (mut emitter, _tx) = test_emitter();
+        // as set over OSC or the control socket, which only clamp to the parameter's range
+        emitter
+            .params
+            .control_mut(&ControlParam::Spray)
+            .set_clamped(1e-9);
+
+        // half a nanosecond is lost to rounding in the middle of the clip, and nothing is left
+        // at its end
+        for begin in [0.5, 1.0] {
+            emitter.handle_message(EmitterMessage::OneShot(OneShot {
+                n: 0.0,
+                length: None,
+                speed: 1.0,
+                gain: 1.0,
+                pan: 0.5,
+                begin: Some(begin),
+                end: None,
+            }));
+            assert!(!grain_onsets(&mut emitter, 1).is_empty());
+        }
+    }
+
+    #[test]
+    fn oneshots_play_next_to_the_mono_voice() {
+        let (mut emitter, _tx) = test_emitter();
+        emitter.params.voice_mode = VoiceMode::Mono;
+        emitter.params.voice_stealing = VoiceStealing::Oldest;
+
+        emitter.handle_message(note_on(60));
+        emitter.handle_message(oneshot(7.0));
+        emitter.handle_message(note_on(62));
+        assert_eq!(mono_and_oneshot_keys(&emitter), (vec![62], vec![67]));
+
+        // a released mono voice starts over without cutting the one-shot short
+        for key in [60, 62] {
+            emitter.handle_message(EmitterMessage::NoteOff {
+                channel: u4::from(0),
+                key: u7::from(key),
+            });
+        }
+        emitter.notes[0].state = NoteState::Finished;
+        emitter.handle_message(note_on(64));
+        assert_eq!(mono_and_oneshot_keys(&emitter), (vec![64], vec![67]));
+
+        // with a single voice left, one-shots only steal from each other
+        emitter.params.polyphony = 1;
+        emitter.handle_message(oneshot(0.0));
+        assert_eq!(mono_and_oneshot_keys(&emitter), (vec![64], vec![60]));
+    }
+
+    /// Frames at which grains are spawned by the notes of `emitter`, over `frames` frames
+    fn grain_onsets(emitter: &mut Emitter<f32>, frames: usize) -> Vec<usize> {
+        (0..frames)
+            .filter(|_| {
+                emitter.update_notes();
+                let spawned = !emitter.grains.is_empty();
+                emitter.grains.clear();
+                emitter.sounding_grains = 0;
+                spawned
+            })
+            .collect()
+    }
+
+    #[test]
+    fn key_tracked_grains_play_in_tune() {
+        let (mut emitter, _tx) = test_emitter();
+        let params = EmitterParams {
+            density_mode: DensityMode::KeyTracked,
+            ..Default::default()
+        };
+        emitter.handle_message(EmitterMessage::Params(Box::new(params)));
+        emitter.handle_message(note_on(69));
+
+        // a second of A4 has 440 pulses, each on the first frame after it was due
+        let onsets = grain_onsets(&mut emitter, SAMPLE_RATE as usize);
+        assert_eq!(onsets.len(), 440);
+        for (i, onset) in onsets.iter().enumerate() {
+            let due = i as f64 * SAMPLE_RATE as f64 / 440.0;
+            assert_eq!(*onset, due.ceil() as usize, "pulse {i}");
+        }
+    }
+
+    #[test]
+    fn synced_grains_keep_to_the_tempo() {
+        let (mut emitter, _tx) = test_emitter();
+        let params = EmitterParams {
+            density_mode: DensityMode::Synced,
+            ..Default::default()
+        };
+        emitter.handle_message(EmitterMessage::Params(Box::new(params)));
+        emitter.handle_message(note_on(60));
+
+        // sixteenth notes at 120 BPM are exactly 6000 frames apart
+        let onsets = grain_onsets(&mut emitter, 100 * 6000);
+        let expected: Vec<_> = (0..100).map(|i| i * 6000).collect();
+        assert_eq!(onsets, expected);
+    }
+
+    /// Onsets of grains synced to a MIDI clock that ticks every `tick_frames` frames
+    fn clock_synced_onsets(tick_frames: usize, grains: usize) -> Vec<usize> {
+        let (mut emitter, _tx) = test_emitter();
+        let params = EmitterParams {
+            density_mode: DensityMode::Synced,
+            tempo_source: TempoSource::MidiClock,
+            ..Default::default()
+        };
+        emitter.handle_message(EmitterMessage::Params(Box::new(params)));
+        emitter.handle_message(note_on(60));
+
+        let mut onsets = Vec::new();
+        let mut frame = 0;
+        while onsets.len() < grains {
+            if frame % tick_frames == 0 {
+                emitter.handle_message(EmitterMessage::ClockTick);
+            }
+            onsets.extend(grain_onsets(&mut emitter, 1).iter().map(|_| frame));
+            frame += 1;
+        }
+        onsets
+    }
+
+    #[test]
+    fn synced_grains_follow_the_midi_clock() {
+        // the tempo is still 120 BPM, which would tick every 1000 frames
+        for tick_frames in [990, 1000, 1010] {
+            // sixteenth notes are six ticks apart
+            let grain_frames = 6 * tick_frames;
+            let onsets = clock_synced_onsets(tick_frames, 100);
+            for (i, onset) in onsets.into_iter().enumerate() {
+                // ahead of a late tick by less than a tick, or on the frame after it
+                let due = i * grain_frames;
+                assert!(
+                    (due.saturating_sub(tick_frames)..=due + 1).contains(&onset),
+                    "grain {i} at frame {onset} instead of {due} with ticks every {tick_frames} frames"
+                );
+            }
+        }
+    }
 }
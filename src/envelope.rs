@@ -4,7 +4,7 @@ use eframe::egui::lerp;
 
 use crate::params::Parameter;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct AdsrEnvelope {
     pub attack: Parameter<Duration>,
     pub decay: Parameter<Duration>,
@@ -67,7 +67,7 @@ impl Default for AdsrEnvelope {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct GrainEnvelope {
     pub amount: Parameter<f32>,
     pub skew: Parameter<f32>,
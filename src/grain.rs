@@ -1,186 +1,174 @@
 use rodio::{
-    source::{Amplify, Speed, UniformSourceIterator},
-    Sample, Source,
+    cpal::{FromSample, Sample as CpalSample},
+    Sample,
 };
 use std::time::Duration;
 
 use crate::{audio_clip::AudioClip, envelope::GrainEnvelope, widgets::waveform::GrainDrawData};
 
-pub struct Grain<I>
-where
-    I: Sample,
-{
-    inner: UniformSourceIterator<Speed<Amplify<GrainInner<I>>>, I>,
-    envelope: GrainEnvelope,
+/// Number of interleaved output channels
+pub const CHANNELS: usize = 2;
+
+pub struct Grain {
+    /// id of the note that spawned this grain
+    pub note_id: u64,
 
-    total_duration: Duration,
-    elapsed_duration: Duration,
-    duration_per_sample: Duration,
+    envelope: GrainEnvelope,
+    amplitude: f32,
+    /// gain of each output channel
+    channel_gains: [f32; CHANNELS],
+
+    /// fractional frame of the audio clip that is read next
+    position: f64,
+    /// clip frames to advance per output frame
+    speed: f64,
+
+    /// gain applied on top of the envelope, decreasing by `fade_step` per frame while fading out
+    fade_gain: f32,
+    fade_step: f32,
+
+    /// frame of the block being rendered at which the grain starts playing
+    start_offset: usize,
+    /// frame of the block being rendered at which the fade out begins
+    fade_offset: usize,
+
+    total_frames: u32,
+    elapsed_frames: u32,
+    /// fraction of a frame that already passed when the grain started playing
+    lag: f32,
     sample_rate: u32,
 
     // just for animating on the GUI
-    start_position: f32,
-    position_per_second: f32,
+    clip_frames: usize,
 }
 
-impl<I> Grain<I>
-where
-    I: Sample,
-{
-    pub fn new(
-        audio_clip: AudioClip<I>,
+impl Grain {
+    pub fn new<I>(
+        note_id: u64,
+        audio_clip: &AudioClip<I>,
         start_position: f32,
         length: Duration,
         speed: f32,
         amplitude: f32,
         envelope: GrainEnvelope,
-    ) -> Grain<I> {
-        let index = {
-            let samples_per_channel = audio_clip.data.len() / audio_clip.channels as usize;
-            (samples_per_channel as f32 * start_position) as usize * audio_clip.channels as usize
-        };
+    ) -> Grain
+    where
+        I: Sample,
+    {
+        let clip_frames = audio_clip.data.len() / audio_clip.channels as usize;
         let sample_rate = audio_clip.sample_rate;
-        let clip_samples = audio_clip.data.len();
-        let duration_per_sample = audio_clip.duration_per_sample().mul_f32(1.0 / speed);
-        let total_duration = length.mul_f32(1.0 / speed);
-        let position_per_second =
-            (speed * sample_rate as f32) / (clip_samples as f32 / audio_clip.channels as f32);
-
-        let inner = UniformSourceIterator::new(
-            GrainInner::new(audio_clip, index)
-                .amplify(amplitude)
-                .speed(speed),
-            2,
-            sample_rate,
-        );
 
         Grain {
-            inner,
+            note_id,
             envelope,
-            total_duration,
-            elapsed_duration: Duration::ZERO,
-            duration_per_sample,
+            amplitude,
+            channel_gains: [1.0; CHANNELS],
+            position: (clip_frames as f32 * start_position) as usize as f64,
+            speed: speed as f64,
+            fade_gain: 1.0,
+            fade_step: 0.0,
+            start_offset: 0,
+            fade_offset: 0,
+            total_frames: (length.as_secs_f32() / speed * sample_rate as f32) as u32,
+            elapsed_frames: 0,
+            lag: 0.0,
             sample_rate,
-            start_position,
-            position_per_second,
+            clip_frames,
         }
     }
 
-    pub fn draw(&self) -> GrainDrawData {
-        let elapsed = self.elapsed_duration.as_secs_f32();
-        GrainDrawData {
-            current_position: self.position_per_second * elapsed + self.start_position,
-            current_progress: elapsed / self.total_duration.as_secs_f32(),
-        }
+    /// Start playing at a frame of the next rendered block instead of its beginning
+    pub fn starting_at(mut self, offset: usize) -> Self {
+        self.start_offset = offset;
+        self
     }
-}
 
-impl<I> Iterator for Grain<I>
-where
-    I: Sample,
-{
-    type Item = I;
-
-    #[inline]
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.elapsed_duration >= self.total_duration {
-            None
-        } else {
-            let factor = self.envelope.amplitude_at(
-                self.elapsed_duration.as_secs_f32() / self.total_duration.as_secs_f32(),
-            );
-
-            let sample = self.inner.next().map(|s| s.amplify(factor));
-
-            self.elapsed_duration += self.duration_per_sample;
-            sample
-        }
+    /// Start a fraction of a frame into the grain, for a grain that was due between two frames
+    pub fn late_by(mut self, frames: f64) -> Self {
+        self.position += self.speed * frames;
+        self.lag = frames as f32;
+        self
     }
 
-    #[inline]
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        self.inner.size_hint()
+    /// Balance the grain between the left (0) and right (1) channel, where 0.5 keeps both at
+    /// full level
+    pub fn panned(mut self, pan: f32) -> Self {
+        self.channel_gains = [(2.0 * (1.0 - pan)).min(1.0), (2.0 * pan).min(1.0)];
+        self
     }
-}
 
-impl<I> Source for Grain<I>
-where
-    I: Sample,
-{
-    fn current_frame_len(&self) -> Option<usize> {
-        None
+    /// Quickly silence the grain before it reaches its end, starting at a frame of the next
+    /// rendered block
+    pub fn fade_out(&mut self, duration: Duration, offset: usize) {
+        let frames = duration.as_secs_f32() * self.sample_rate as f32;
+        self.fade_step = self.fade_gain / frames.max(1.0);
+        self.fade_offset = offset;
     }
 
-    fn channels(&self) -> u16 {
-        2
+    pub fn is_fading(&self) -> bool {
+        self.fade_step > 0.0
     }
 
-    fn sample_rate(&self) -> u32 {
-        self.sample_rate
+    /// Gain the grain is currently played back with
+    pub fn current_gain(&self) -> f32 {
+        self.amplitude
+            * self.fade_gain
+            * self
+                .envelope
+                .amplitude_at((self.elapsed_frames as f32 + self.lag) / self.total_frames as f32)
     }
 
-    fn total_duration(&self) -> Option<Duration> {
-        Some(self.total_duration)
+    pub fn is_finished(&self) -> bool {
+        self.elapsed_frames >= self.total_frames
+            || self.fade_gain <= 0.0
+            || self.position as usize + 1 >= self.clip_frames
     }
-}
 
-/// Just plays raw samples from audio clip
-struct GrainInner<I>
-where
-    I: Sample,
-{
-    audio_clip: AudioClip<I>,
-    index: usize,
-}
-
-impl<I> GrainInner<I>
-where
-    I: Sample,
-{
-    fn new(audio_clip: AudioClip<I>, start_index: usize) -> Self {
-        Self {
-            audio_clip,
-            index: start_index,
+    pub fn draw(&self) -> GrainDrawData {
+        GrainDrawData {
+            current_position: self.position as f32 / self.clip_frames as f32,
+            current_progress: self.elapsed_frames as f32 / self.total_frames as f32,
         }
     }
-}
-
-impl<I> Iterator for GrainInner<I>
-where
-    I: Sample,
-{
-    type Item = I;
-
-    #[inline]
-    fn next(&mut self) -> Option<Self::Item> {
-        let sample = self.audio_clip.data.get(self.index);
-        self.index += 1;
-        sample.copied()
-    }
-
-    #[inline]
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.audio_clip.data.len(), Some(self.audio_clip.data.len()))
-    }
-}
 
-impl<I> Source for GrainInner<I>
-where
-    I: Sample,
-{
-    fn current_frame_len(&self) -> Option<usize> {
-        None
-    }
-
-    fn channels(&self) -> u16 {
-        self.audio_clip.channels
-    }
-
-    fn sample_rate(&self) -> u32 {
-        self.audio_clip.sample_rate
-    }
+    /// Mix the grain into a block of interleaved output frames
+    pub fn render_block<I>(&mut self, audio_clip: &AudioClip<I>, block: &mut [f32])
+    where
+        I: Sample,
+        f32: FromSample<I>,
+    {
+        let clip_channels = audio_clip.channels as usize;
+        let frames = block.chunks_exact_mut(CHANNELS).enumerate();
+        for (i, frame) in frames.skip(self.start_offset) {
+            if self.is_finished() {
+                break;
+            }
+
+            // read between the two neighbouring clip frames, just like a linear resampler would
+            let index = self.position as usize;
+            let fraction = (self.position - index as f64) as f32;
+
+            let gain = self.current_gain();
+
+            for ((channel, out), channel_gain) in
+                frame.iter_mut().enumerate().zip(self.channel_gains)
+            {
+                // mono clips play on all channels, surplus clip channels are dropped
+                let channel = channel.min(clip_channels - 1);
+                let a = f32::from_sample(audio_clip.data[index * clip_channels + channel]);
+                let b = f32::from_sample(audio_clip.data[(index + 1) * clip_channels + channel]);
+                *out += (a + (b - a) * fraction) * gain * channel_gain;
+            }
+
+            if i >= self.fade_offset {
+                self.fade_gain -= self.fade_step;
+            }
+            self.position += self.speed;
+            self.elapsed_frames += 1;
+        }
 
-    fn total_duration(&self) -> Option<Duration> {
-        None
+        // offsets only refer to the block that was just rendered
+        self.start_offset = 0;
+        self.fade_offset = 0;
     }
 }
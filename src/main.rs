@@ -1,17 +1,32 @@
+mod alloc_guard;
 mod app;
 mod audio_clip;
+mod bench;
+#[cfg(unix)]
+mod control;
 mod emitter;
 mod envelope;
 mod grain;
 mod midi;
 mod numeric;
+mod osc;
 mod params;
+mod presets;
+mod router;
+mod settings;
+mod smoothing;
+mod tempo;
 mod widgets;
 
 use app::NebulizerApp;
 use eframe::egui::Vec2;
 
 fn main() {
+    if std::env::args().any(|arg| arg == "--bench") {
+        bench::run();
+        return;
+    }
+
     let app = NebulizerApp::new();
 
     let mut native_options = eframe::NativeOptions::default();
@@ -1,49 +1,904 @@
-use midir::{MidiInput, MidiInputConnection, MidiInputPort, MidiInputPorts};
-use midly::{live::LiveEvent, num::u4, MidiMessage};
+use std::{
+    fmt,
+    sync::{mpsc::Sender, Arc, Mutex},
+};
+
+use midir::{
+    MidiInput, MidiInputConnection, MidiInputPort, MidiOutput, MidiOutputConnection, MidiOutputPort,
+};
+use midly::{
+    live::LiveEvent,
+    num::{u4, u7},
+};
+use strum::VariantArray;
+
+use crate::{
+    params::{ControlKind, ControlParam, ControlSource, EmitterParams, NUM_CONTROL_PARAMS},
+    router::RouterUpdate,
+};
+
+/// Name of the input port that nebulizer can offer to other programs
+#[cfg(unix)]
+pub const VIRTUAL_PORT_NAME: &str = "nebulizer in";
 
 pub struct MidiConfig {
-    pub midi_in: MidiInput,
-    pub ports: MidiInputPorts,
-    pub connection: Option<(String, MidiInputConnection<()>)>,
+    midi_in: Option<MidiInput>,
+    /// available input ports along with their names
+    pub ports: Vec<(String, MidiInputPort)>,
+    /// inputs chosen by the user, including ones waiting for their port to come back
+    pub connections: Vec<MidiConnection>,
+    /// latest problem with MIDI input, to be shown to the user
+    pub error: Option<String>,
+
+    midi_out: Option<MidiOutput>,
+    /// available output ports along with their names
+    pub output_ports: Vec<(String, MidiOutputPort)>,
+    pub output: Option<MidiOut>,
+    /// echo incoming notes to the output
+    pub thru: bool,
+    /// channel that controller feedback is sent on
+    pub feedback_channel: u4,
+}
+
+/// Output port that thru notes and controller feedback are sent to, shared between the GUI and
+/// the MIDI input threads
+#[derive(Clone)]
+pub struct MidiOut {
+    pub port_name: String,
+    connection: Arc<Mutex<MidiOutputConnection>>,
+}
+
+impl MidiOut {
+    pub fn send(&self, message: &[u8]) {
+        if let Ok(mut connection) = self.connection.lock() {
+            let _ = connection.send(message);
+        }
+    }
+}
+
+/// Input port that is being listened to, or will be as soon as it's available again
+pub struct MidiConnection {
+    pub port_name: String,
+    pub channel_mode: ChannelMode,
+    link: Option<MidiLink>,
+}
+
+struct MidiLink {
+    /// forwards changes made in the GUI to the router on this connection's MIDI thread
+    router: Sender<RouterUpdate>,
+    _connection: MidiInputConnection<()>,
+}
+
+impl MidiConnection {
+    pub fn is_connected(&self) -> bool {
+        self.link.is_some()
+    }
+
+    pub fn update_router(&self, update: RouterUpdate) {
+        if let Some(link) = &self.link {
+            let _ = link.router.send(update);
+        }
+    }
 }
 
 impl MidiConfig {
     pub fn new() -> MidiConfig {
-        let midi_in = MidiInput::new("Nebulizer MIDI in").unwrap();
-        let ports = midi_in.ports();
+        let mut config = MidiConfig {
+            midi_in: None,
+            ports: Vec::new(),
+            connections: Vec::new(),
+            error: None,
+            midi_out: None,
+            output_ports: Vec::new(),
+            output: None,
+            thru: false,
+            feedback_channel: u4::from(0),
+        };
+        config.refresh_ports();
+        config
+    }
+
+    pub fn refresh_ports(&mut self) {
+        self.error = None;
+        self.poll_ports();
+    }
+
+    /// Update the list of available ports and notice connections whose port has gone away
+    pub fn poll_ports(&mut self) {
+        if self.midi_in.is_none() {
+            match MidiInput::new("Nebulizer MIDI in") {
+                Ok(midi_in) => self.midi_in = Some(midi_in),
+                Err(err) => {
+                    self.error = Some(format!("MIDI input is unavailable: {err}"));
+                    return;
+                }
+            }
+        }
+
+        if let Some(midi_in) = &self.midi_in {
+            // ports that disappear while enumerating them are simply left out
+            self.ports = midi_in
+                .ports()
+                .into_iter()
+                .filter_map(|port| Some((midi_in.port_name(&port).ok()?, port)))
+                .filter(|(name, _)| !is_own_port(name))
+                .collect();
+        }
 
-        MidiConfig {
-            midi_in,
-            ports,
-            connection: None,
+        if self.midi_out.is_none() {
+            match MidiOutput::new("Nebulizer MIDI out") {
+                Ok(midi_out) => self.midi_out = Some(midi_out),
+                Err(err) => self.error = Some(format!("MIDI output is unavailable: {err}")),
+            }
+        }
+
+        if let Some(midi_out) = &self.midi_out {
+            self.output_ports = midi_out
+                .ports()
+                .into_iter()
+                .filter_map(|port| Some((midi_out.port_name(&port).ok()?, port)))
+                // sending to our own input would feed notes straight back in
+                .filter(|(name, _)| !is_own_port(name))
+                .collect();
+        }
+
+        for connection in &mut self.connections {
+            let name = &connection.port_name;
+            if !is_own_port(name) && !self.ports.iter().any(|(port_name, _)| port_name == name) {
+                connection.link = None;
+            }
         }
     }
 
-    pub fn refresh_ports(&mut self) {
-        self.ports = self.midi_in.ports()
+    /// Add an input that gets connected once its port shows up
+    pub fn remember(&mut self, port_name: &str, channel_mode: ChannelMode) {
+        self.connections.push(MidiConnection {
+            port_name: port_name.to_string(),
+            channel_mode,
+            link: None,
+        });
     }
 
-    pub fn connect<F>(&mut self, port: &MidiInputPort, mut callback: F)
-    where
-        F: FnMut(u4, MidiMessage) + Send + 'static,
+    /// Whether a port belongs to one of the chosen inputs, connected or not
+    pub fn is_remembered(&self, port_name: &str) -> bool {
+        self.find_connection(port_name).is_some()
+    }
+
+    /// Names of chosen inputs that are currently disconnected
+    pub fn waiting_inputs(&self) -> Vec<String> {
+        self.connections
+            .iter()
+            .filter(|c| !c.is_connected())
+            .map(|c| c.port_name.clone())
+            .collect()
+    }
+
+    /// Available port of the same device as `port_name`
+    pub fn find_port(&self, port_name: &str) -> Option<(String, MidiInputPort)> {
+        self.ports
+            .iter()
+            .find(|(name, _)| device_name(name) == device_name(port_name))
+            .cloned()
+    }
+
+    fn find_connection(&self, port_name: &str) -> Option<usize> {
+        self.connections
+            .iter()
+            .position(|c| device_name(&c.port_name) == device_name(port_name))
+    }
+
+    /// Listen to a port, passing every event to `callback` along with its timestamp in
+    /// microseconds. Messages that can't be parsed are skipped.
+    /// `router` should reach the router that `callback` hands the events to.
+    pub fn connect<F>(
+        &mut self,
+        name: &str,
+        port: &MidiInputPort,
+        router: Sender<RouterUpdate>,
+        callback: F,
+    ) where
+        F: FnMut(u64, LiveEvent) + Send + 'static,
     {
-        let port_name = self.midi_in.port_name(port).unwrap();
         // have to make a new one because `connect` takes ownership for some reason
-        let midi_input = MidiInput::new("Connection input (?)").unwrap();
-        let conn = midi_input.connect(
-            &port,
-            "nebulizer-input-port",
-            move |_stamp, msg_raw, _| {
-                let event = LiveEvent::parse(msg_raw).unwrap();
-                match event {
-                    LiveEvent::Midi { channel, message } => {
-                        callback(channel, message);
-                    }
-                    _ => {}
+        let midi_input = match MidiInput::new("Connection input (?)") {
+            Ok(midi_input) => midi_input,
+            Err(err) => {
+                self.error = Some(format!("Can't connect to {name}: {err}"));
+                return;
+            }
+        };
+
+        let conn = midi_input.connect(port, "nebulizer-input-port", parse_events(callback), ());
+        self.add_connection(name, router, conn);
+    }
+
+    /// Open a virtual input port named [`VIRTUAL_PORT_NAME`] that other programs on this
+    /// machine can send to, otherwise behaving just like [`MidiConfig::connect`]
+    #[cfg(unix)]
+    pub fn open_virtual_port<F>(&mut self, router: Sender<RouterUpdate>, callback: F)
+    where
+        F: FnMut(u64, LiveEvent) + Send + 'static,
+    {
+        use midir::os::unix::VirtualInput;
+
+        let midi_input = match MidiInput::new("nebulizer") {
+            Ok(midi_input) => midi_input,
+            Err(err) => {
+                self.error = Some(format!("Can't open {VIRTUAL_PORT_NAME}: {err}"));
+                return;
+            }
+        };
+
+        let conn = midi_input.create_virtual(VIRTUAL_PORT_NAME, parse_events(callback), ());
+        self.add_connection(VIRTUAL_PORT_NAME, router, conn);
+    }
+
+    /// Send thru notes and controller feedback to a port from now on
+    pub fn connect_output(&mut self, name: &str, port: &MidiOutputPort) {
+        // like inputs, outputs are consumed by connecting
+        let midi_output = match MidiOutput::new("Connection output") {
+            Ok(midi_output) => midi_output,
+            Err(err) => {
+                self.error = Some(format!("Can't connect to {name}: {err}"));
+                return;
+            }
+        };
+
+        match midi_output.connect(port, "nebulizer-output-port") {
+            Ok(connection) => {
+                self.output = Some(MidiOut {
+                    port_name: name.to_string(),
+                    connection: Arc::new(Mutex::new(connection)),
+                });
+                self.error = None;
+            }
+            Err(err) => self.error = Some(format!("Can't connect to {name}: {err}")),
+        }
+    }
+
+    /// Output that routers should echo notes to
+    pub fn thru_output(&self) -> Option<MidiOut> {
+        self.output.clone().filter(|_| self.thru)
+    }
+
+    /// Attach a new connection to the input it belongs to, or add it as a new input
+    fn add_connection<E: fmt::Display>(
+        &mut self,
+        name: &str,
+        router: Sender<RouterUpdate>,
+        conn: Result<MidiInputConnection<()>, E>,
+    ) {
+        let conn = match conn {
+            Ok(conn) => conn,
+            Err(err) => {
+                self.error = Some(format!("Can't connect to {name}: {err}"));
+                return;
+            }
+        };
+
+        let index = self.find_connection(name).unwrap_or_else(|| {
+            self.remember(name, ChannelMode::Omni);
+            self.connections.len() - 1
+        });
+        let connection = &mut self.connections[index];
+        // the port might have been given new numbers when it came back
+        connection.port_name = name.to_string();
+        connection.link = Some(MidiLink {
+            router,
+            _connection: conn,
+        });
+        connection.update_router(RouterUpdate::ChannelMode(connection.channel_mode));
+        self.error = None;
+    }
+}
+
+/// Port name without the client and port numbers that ALSA appends, which can change when a
+/// device is plugged back in
+fn device_name(port_name: &str) -> &str {
+    let Some((device, numbers)) = port_name.rsplit_once(' ') else {
+        return port_name;
+    };
+    let is_number = |n: &str| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit());
+    match numbers.split_once(':') {
+        Some((client, port)) if is_number(client) && is_number(port) => device,
+        _ => port_name,
+    }
+}
+
+/// Whether a port is nebulizer's own virtual input, which it shouldn't listen to twice
+fn is_own_port(name: &str) -> bool {
+    #[cfg(unix)]
+    return name.contains(VIRTUAL_PORT_NAME);
+    #[cfg(not(unix))]
+    return false;
+}
+
+/// Turn raw MIDI input into events for `callback`, skipping messages that can't be parsed
+fn parse_events<F>(mut callback: F) -> impl FnMut(u64, &[u8], &mut ()) + Send + 'static
+where
+    F: FnMut(u64, LiveEvent) + Send + 'static,
+{
+    move |stamp, msg_raw, _| match LiveEvent::parse(msg_raw) {
+        Ok(event) => callback(stamp, event),
+        Err(err) => eprintln!("Skipping MIDI message {msg_raw:02x?}: {err}"),
+    }
+}
+
+/// Which MIDI channels notes are received on
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChannelMode {
+    /// Listen on all channels
+    Omni,
+    /// All notes and controls arrive on one channel
+    Single(u4),
+    /// MIDI Polyphonic Expression, where every note gets its own member channel
+    Mpe(MpeZone),
+}
+
+impl ChannelMode {
+    /// Whether messages on this channel should be handled at all
+    pub fn accepts(&self, channel: u4) -> bool {
+        match self {
+            ChannelMode::Omni => true,
+            ChannelMode::Single(c) => *c == channel,
+            ChannelMode::Mpe(zone) => {
+                channel == zone.master_channel() || zone.is_member_channel(channel)
+            }
+        }
+    }
+
+    /// Whether per-note expression (pitch bend, pressure, timbre) is sent on this channel
+    pub fn is_expression_channel(&self, channel: u4) -> bool {
+        match self {
+            ChannelMode::Omni | ChannelMode::Single(_) => false,
+            ChannelMode::Mpe(zone) => zone.is_member_channel(channel),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ZoneSide {
+    /// Master channel 1, member channels counting up from 2
+    Lower,
+    /// Master channel 16, member channels counting down from 15
+    Upper,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct MpeZone {
+    pub side: ZoneSide,
+    /// Number of member channels in the zone (1 to 15)
+    pub member_channels: u8,
+}
+
+impl Default for MpeZone {
+    fn default() -> Self {
+        Self {
+            side: ZoneSide::Lower,
+            member_channels: 15,
+        }
+    }
+}
+
+impl MpeZone {
+    /// Channel used for zone-wide messages
+    pub fn master_channel(&self) -> u4 {
+        match self.side {
+            ZoneSide::Lower => u4::from(0),
+            ZoneSide::Upper => u4::from(15),
+        }
+    }
+
+    pub fn is_member_channel(&self, channel: u4) -> bool {
+        let channel = channel.as_int();
+        let members = self.member_channels.clamp(1, 15);
+        match self.side {
+            ZoneSide::Lower => (1..=members).contains(&channel),
+            ZoneSide::Upper => (15 - members..=14).contains(&channel),
+        }
+    }
+}
+
+/// A (possibly high resolution) value received from a MIDI controller
+pub struct ControlValue {
+    pub source: ControlSource,
+    /// Normalized value [0,1]
+    pub value: f64,
+}
+
+impl ControlValue {
+    fn seven_bit(source: ControlSource, value: u8) -> Self {
+        Self {
+            source,
+            value: value as f64 / 127.0,
+        }
+    }
+
+    fn fourteen_bit(source: ControlSource, value: u16) -> Self {
+        Self {
+            source,
+            value: value as f64 / 16383.0,
+        }
+    }
+
+    /// Control change messages that send this value to its controller, the reverse of what
+    /// [`ControlDecoder`] reassembles
+    pub fn encode(&self, channel: u4) -> Vec<[u8; 3]> {
+        let status = 0xb0 | channel.as_int();
+        let value = self.value.clamp(0.0, 1.0);
+        let fine = (value * 16383.0).round() as u16;
+        let (msb, lsb) = ((fine >> 7) as u8, (fine & 0x7f) as u8);
+        let number = self.source.number;
+        let (number_msb, number_lsb) = ((number >> 7) as u8, (number & 0x7f) as u8);
+
+        match self.source.kind {
+            ControlKind::Cc => vec![[status, number as u8, (value * 127.0).round() as u8]],
+            ControlKind::Cc14 => vec![
+                [status, number as u8, msb],
+                [status, number as u8 + 32, lsb],
+            ],
+            ControlKind::Nrpn => vec![
+                [status, NRPN_MSB, number_msb],
+                [status, NRPN_LSB, number_lsb],
+                [status, DATA_ENTRY_MSB, msb],
+                [status, DATA_ENTRY_LSB, lsb],
+            ],
+            ControlKind::Rpn => vec![
+                [status, RPN_MSB, number_msb],
+                [status, RPN_LSB, number_lsb],
+                [status, DATA_ENTRY_MSB, msb],
+                [status, DATA_ENTRY_LSB, lsb],
+            ],
+        }
+    }
+}
+
+const DATA_ENTRY_MSB: u8 = 6;
+const DATA_ENTRY_LSB: u8 = 38;
+const DATA_INCREMENT: u8 = 96;
+const DATA_DECREMENT: u8 = 97;
+const NRPN_LSB: u8 = 98;
+const NRPN_MSB: u8 = 99;
+const RPN_LSB: u8 = 100;
+const RPN_MSB: u8 = 101;
+
+/// Reassembles 14-bit CCs and (N)RPNs out of the individual control change messages they are
+/// sent as
+#[derive(Default)]
+pub struct ControlDecoder {
+    channels: [ChannelControls; 16],
+}
+
+#[derive(Clone, Copy, Default)]
+struct ChannelControls {
+    /// most recent MSB of each 14-bit CC
+    msb: [u8; 32],
+    /// which kind of parameter number is currently selected, if any
+    param_kind: Option<ControlKind>,
+    /// MSB and LSB of the selected parameter number
+    param_number: (u8, u8),
+    /// 14-bit value of the selected parameter
+    data: u16,
+}
+
+impl ChannelControls {
+    fn selected_param(&self) -> Option<ControlSource> {
+        let kind = self.param_kind?;
+        let (msb, lsb) = self.param_number;
+        Some(ControlSource::new(kind, (msb as u16) << 7 | lsb as u16))
+    }
+
+    fn select_param(&mut self, kind: ControlKind, msb: Option<u8>, lsb: Option<u8>) {
+        if self.param_kind != Some(kind) {
+            self.param_number = (0, 0);
+        }
+        self.param_kind = Some(kind);
+        if let Some(msb) = msb {
+            self.param_number.0 = msb;
+        }
+        if let Some(lsb) = lsb {
+            self.param_number.1 = lsb;
+        }
+
+        // RPN 127/127 is the "null" parameter which deselects any (N)RPN
+        if kind == ControlKind::Rpn && self.param_number == (127, 127) {
+            self.param_kind = None;
+        }
+    }
+}
+
+impl ControlDecoder {
+    /// Feed in one control change and get back every controller value that it updated.
+    /// High resolution values come before the plain 7-bit one.
+    pub fn decode(
+        &mut self,
+        channel: u4,
+        controller: u7,
+        value: u7,
+    ) -> impl Iterator<Item = ControlValue> {
+        let state = &mut self.channels[channel.as_int() as usize];
+        let (controller, value) = (controller.as_int(), value.as_int());
+        let plain = ControlValue::seven_bit(ControlSource::cc(controller.into()), value);
+
+        let (high_res, plain) = match (controller, state.selected_param()) {
+            (NRPN_MSB, _) => {
+                state.select_param(ControlKind::Nrpn, Some(value), None);
+                (None, None)
+            }
+            (NRPN_LSB, _) => {
+                state.select_param(ControlKind::Nrpn, None, Some(value));
+                (None, None)
+            }
+            (RPN_MSB, _) => {
+                state.select_param(ControlKind::Rpn, Some(value), None);
+                (None, None)
+            }
+            (RPN_LSB, _) => {
+                state.select_param(ControlKind::Rpn, None, Some(value));
+                (None, None)
+            }
+            (DATA_ENTRY_MSB, Some(param)) => {
+                state.data = (value as u16) << 7;
+                (Some(ControlValue::seven_bit(param, value)), None)
+            }
+            (DATA_ENTRY_LSB, Some(param)) => {
+                state.data = state.data & !0x7f | value as u16;
+                (Some(ControlValue::fourteen_bit(param, state.data)), None)
+            }
+            (DATA_INCREMENT, Some(param)) => {
+                state.data = (state.data + 1).min(16383);
+                (Some(ControlValue::fourteen_bit(param, state.data)), None)
+            }
+            (DATA_DECREMENT, Some(param)) => {
+                state.data = state.data.saturating_sub(1);
+                (Some(ControlValue::fourteen_bit(param, state.data)), None)
+            }
+            (0..=31, _) => {
+                // 14-bit mappings follow the plain MSB until the LSB refines it
+                state.msb[controller as usize] = value;
+                (None, Some(plain))
+            }
+            (32..=63, _) => {
+                let msb = state.msb[controller as usize - 32];
+                let source = ControlSource::new(ControlKind::Cc14, controller as u16 - 32);
+                let combined = (msb as u16) << 7 | value as u16;
+                (
+                    Some(ControlValue::fourteen_bit(source, combined)),
+                    Some(plain),
+                )
+            }
+            _ => (None, Some(plain)),
+        };
+
+        high_res.into_iter().chain(plain)
+    }
+}
+
+/// Universal real time SysEx messages are addressed to all devices of this kind
+const UNIVERSAL_REAL_TIME: u8 = 0x7f;
+/// Device control sub-IDs of the master volume message
+const DEVICE_CONTROL: u8 = 0x04;
+const MASTER_VOLUME: u8 = 0x01;
+
+/// Volume in range [0,1] if `sysex` (without its F0 and F7 bytes) is a universal master volume
+/// message, which is sent to any device ID
+pub fn master_volume(sysex: &[u7]) -> Option<f32> {
+    let [id, _device, sub_id, sub_id2, lsb, msb] = sysex else {
+        return None;
+    };
+    if (id.as_int(), sub_id.as_int(), sub_id2.as_int())
+        != (UNIVERSAL_REAL_TIME, DEVICE_CONTROL, MASTER_VOLUME)
+    {
+        return None;
+    }
+    Some(((msb.as_int() as u16) << 7 | lsb.as_int() as u16) as f32 / 16383.0)
+}
+
+/// Sends the values of mapped parameters back to their controllers whenever they change
+/// elsewhere, so that motorized faders and LED rings stay in sync
+pub struct CcFeedback {
+    /// parameter values as the controllers are known to show them
+    known: [f64; NUM_CONTROL_PARAMS],
+}
+
+impl CcFeedback {
+    pub fn new(params: &EmitterParams) -> Self {
+        let mut feedback = Self {
+            known: [0.0; NUM_CONTROL_PARAMS],
+        };
+        feedback.observe(params);
+        feedback
+    }
+
+    /// Take note of the current values without sending them, e.g. because they came from the
+    /// controllers in the first place
+    pub fn observe(&mut self, params: &EmitterParams) {
+        for param in ControlParam::VARIANTS {
+            self.known[param.index()] = params.control(param).value();
+        }
+    }
+
+    /// Have every mapped value sent next time, to bring newly connected controllers up to date
+    pub fn forget(&mut self) {
+        self.known = [f64::NAN; NUM_CONTROL_PARAMS];
+    }
+
+    /// Send every mapped parameter that changed since it was last observed or sent
+    pub fn send_changes(&mut self, params: &EmitterParams, output: Option<&MidiOut>, channel: u4) {
+        for param in ControlParam::VARIANTS {
+            let control = params.control(param);
+            let value = control.value();
+            if value == self.known[param.index()] {
+                continue;
+            }
+            self.known[param.index()] = value;
+
+            let Some(output) = output else {
+                continue;
+            };
+            for mapping in params.midi_cc_map.iter().filter(|m| m.param == *param) {
+                let control = ControlValue {
+                    source: mapping.source,
+                    value: mapping.unmap(control.get_normalized()),
+                };
+                for message in control.encode(channel) {
+                    output.send(&message);
                 }
-            },
-            (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Controllers and 14-bit values that a control change updated
+    fn decode(decoder: &mut ControlDecoder, controller: u8, value: u8) -> Vec<(String, u16)> {
+        decoder
+            .decode(u4::from(0), u7::from(controller), u7::from(value))
+            .map(|control| {
+                let value = (control.value * 16383.0).round() as u16;
+                (control.source.to_string(), value)
+            })
+            .collect()
+    }
+
+    fn seven_bit(value: u16) -> u16 {
+        // 16383 = 127 * 129
+        value * 129
+    }
+
+    #[test]
+    fn fourteen_bit_cc_is_assembled_from_msb_then_lsb() {
+        let mut decoder = ControlDecoder::default();
+        assert_eq!(
+            decode(&mut decoder, 7, 64),
+            [("CC 7".to_string(), seven_bit(64))]
+        );
+        assert_eq!(
+            decode(&mut decoder, 39, 10),
+            [
+                ("CC 7/39".to_string(), 64 << 7 | 10),
+                ("CC 39".to_string(), seven_bit(10)),
+            ]
+        );
+
+        // a new MSB starts over without the old LSB
+        decode(&mut decoder, 7, 1);
+        assert_eq!(
+            decode(&mut decoder, 39, 0)[0],
+            ("CC 7/39".to_string(), 1 << 7)
+        );
+    }
+
+    #[test]
+    fn nrpn_data_entry_goes_to_the_selected_parameter() {
+        let mut decoder = ControlDecoder::default();
+        assert!(decode(&mut decoder, NRPN_MSB, 1).is_empty());
+        assert!(decode(&mut decoder, NRPN_LSB, 2).is_empty());
+        assert_eq!(
+            decode(&mut decoder, DATA_ENTRY_MSB, 64),
+            [("NRPN 130".to_string(), seven_bit(64))]
+        );
+        assert_eq!(
+            decode(&mut decoder, DATA_ENTRY_LSB, 5),
+            [("NRPN 130".to_string(), 64 << 7 | 5)]
+        );
+    }
+
+    #[test]
+    fn selecting_an_rpn_replaces_the_nrpn() {
+        let mut decoder = ControlDecoder::default();
+        decode(&mut decoder, NRPN_MSB, 1);
+        decode(&mut decoder, NRPN_LSB, 2);
+        // the parameter number starts over instead of keeping the NRPN's MSB
+        decode(&mut decoder, RPN_LSB, 0);
+        assert_eq!(
+            decode(&mut decoder, DATA_ENTRY_MSB, 2),
+            [("RPN 0".to_string(), seven_bit(2))]
+        );
+    }
+
+    #[test]
+    fn null_rpn_deselects_the_parameter() {
+        let mut decoder = ControlDecoder::default();
+        decode(&mut decoder, RPN_MSB, 0);
+        decode(&mut decoder, RPN_LSB, 0);
+        decode(&mut decoder, RPN_MSB, 127);
+        decode(&mut decoder, RPN_LSB, 127);
+        // data entry is a plain CC again
+        assert_eq!(
+            decode(&mut decoder, DATA_ENTRY_MSB, 3),
+            [("CC 6".to_string(), seven_bit(3))]
+        );
+        assert_eq!(
+            decode(&mut decoder, DATA_INCREMENT, 1),
+            [("CC 96".to_string(), seven_bit(1))]
+        );
+    }
+
+    #[test]
+    fn data_increment_and_decrement_step_the_value() {
+        let mut decoder = ControlDecoder::default();
+        decode(&mut decoder, NRPN_MSB, 0);
+        decode(&mut decoder, NRPN_LSB, 1);
+        decode(&mut decoder, DATA_ENTRY_MSB, 64);
+        decode(&mut decoder, DATA_ENTRY_LSB, 5);
+
+        assert_eq!(
+            decode(&mut decoder, DATA_INCREMENT, 0),
+            [("NRPN 1".to_string(), (64 << 7 | 5) + 1)]
+        );
+        decode(&mut decoder, DATA_DECREMENT, 0);
+        assert_eq!(
+            decode(&mut decoder, DATA_DECREMENT, 0),
+            [("NRPN 1".to_string(), (64 << 7 | 5) - 1)]
+        );
+
+        // the value stays within 14 bits
+        decode(&mut decoder, DATA_ENTRY_MSB, 0);
+        assert_eq!(
+            decode(&mut decoder, DATA_DECREMENT, 0),
+            [("NRPN 1".to_string(), 0)]
+        );
+        decode(&mut decoder, DATA_ENTRY_MSB, 127);
+        decode(&mut decoder, DATA_ENTRY_LSB, 127);
+        assert_eq!(
+            decode(&mut decoder, DATA_INCREMENT, 0),
+            [("NRPN 1".to_string(), 16383)]
+        );
+    }
+
+    #[test]
+    fn channels_are_decoded_separately() {
+        let mut decoder = ControlDecoder::default();
+        decode(&mut decoder, NRPN_MSB, 0);
+        decode(&mut decoder, NRPN_LSB, 1);
+        let other_channel: Vec<_> = decoder
+            .decode(u4::from(1), u7::from(DATA_ENTRY_MSB), u7::from(10))
+            .map(|control| control.source.to_string())
+            .collect();
+        assert_eq!(other_channel, ["CC 6"]);
+    }
+
+    #[test]
+    fn master_volume_is_read_from_universal_sysex() {
+        let sysex = |bytes: &[u8]| bytes.iter().map(|b| u7::from(*b)).collect::<Vec<_>>();
+        assert_eq!(
+            master_volume(&sysex(&[0x7f, 0x7f, 0x04, 0x01, 0x7f, 0x7f])),
+            Some(1.0)
+        );
+        assert_eq!(
+            master_volume(&sysex(&[0x7f, 0x10, 0x04, 0x01, 0x00, 0x00])),
+            Some(0.0)
+        );
+        let half = master_volume(&sysex(&[0x7f, 0x7f, 0x04, 0x01, 0x00, 0x40])).unwrap();
+        assert!((half - 0.5).abs() < 0.001, "{half}");
+
+        // balance, a truncated message and a manufacturer's message
+        assert_eq!(
+            master_volume(&sysex(&[0x7f, 0x7f, 0x04, 0x02, 0x00, 0x40])),
+            None
+        );
+        assert_eq!(master_volume(&sysex(&[0x7f, 0x7f, 0x04, 0x01, 0x00])), None);
+        assert_eq!(
+            master_volume(&sysex(&[0x41, 0x10, 0x04, 0x01, 0x00, 0x40])),
+            None
+        );
+        assert_eq!(master_volume(&[]), None);
+    }
+
+    #[test]
+    fn encoded_values_decode_to_themselves() {
+        for kind in ControlKind::VARIANTS {
+            let source = ControlSource::new(*kind, 3);
+            let sent = ControlValue { source, value: 0.3 };
+            let mut decoder = ControlDecoder::default();
+            let received: Vec<_> = sent
+                .encode(u4::from(0))
+                .into_iter()
+                .flat_map(|[_, controller, value]| decode(&mut decoder, controller, value))
+                .filter(|(name, _)| *name == source.to_string())
+                .collect();
+            let expected = match kind {
+                ControlKind::Cc => seven_bit((0.3f64 * 127.0).round() as u16),
+                _ => (0.3f64 * 16383.0).round() as u16,
+            };
+            assert_eq!(received.last().unwrap().1, expected, "{source}");
+        }
+    }
+
+    fn channels(accepted: impl Fn(u4) -> bool) -> Vec<u8> {
+        (0..16)
+            .map(u4::from)
+            .filter(|channel| accepted(*channel))
+            .map(|channel| channel.as_int())
+            .collect()
+    }
+
+    #[test]
+    fn mpe_zones_take_their_master_and_member_channels() {
+        let lower = MpeZone {
+            side: ZoneSide::Lower,
+            member_channels: 3,
+        };
+        assert_eq!(lower.master_channel(), u4::from(0));
+        assert_eq!(channels(|c| lower.is_member_channel(c)), [1, 2, 3]);
+
+        let upper = MpeZone {
+            side: ZoneSide::Upper,
+            member_channels: 3,
+        };
+        assert_eq!(upper.master_channel(), u4::from(15));
+        assert_eq!(channels(|c| upper.is_member_channel(c)), [12, 13, 14]);
+
+        // the master channel is never a member, even in a full zone
+        let full = MpeZone::default();
+        assert_eq!(
+            channels(|c| full.is_member_channel(c)),
+            (1..=15).collect::<Vec<_>>()
+        );
+        let full = MpeZone {
+            side: ZoneSide::Upper,
+            ..full
+        };
+        assert_eq!(
+            channels(|c| full.is_member_channel(c)),
+            (0..=14).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn channel_modes_accept_their_channels() {
+        assert_eq!(channels(|c| ChannelMode::Omni.accepts(c)).len(), 16);
+        assert_eq!(
+            channels(|c| ChannelMode::Single(u4::from(9)).accepts(c)),
+            [9]
+        );
+
+        let zone = MpeZone {
+            side: ZoneSide::Upper,
+            member_channels: 2,
+        };
+        let mpe = ChannelMode::Mpe(zone);
+        assert_eq!(channels(|c| mpe.accepts(c)), [13, 14, 15]);
+        // expression only comes from members, the master channel is for the whole zone
+        assert_eq!(channels(|c| mpe.is_expression_channel(c)), [13, 14]);
+        assert!(channels(|c| ChannelMode::Single(u4::from(0)).is_expression_channel(c)).is_empty());
+    }
+
+    #[test]
+    fn device_names_leave_out_alsa_port_numbers() {
+        assert_eq!(device_name("Keystation 49 MK3 24:0"), "Keystation 49 MK3");
+        assert_eq!(
+            device_name("Midi Through:Midi Through Port-0 14:0"),
+            "Midi Through:Midi Through Port-0"
         );
-        self.connection = conn.ok().map(|c| (port_name, c));
+        // names from other backends are kept whole
+        assert_eq!(device_name("Keystation 49"), "Keystation 49");
+        assert_eq!(device_name("loopMIDI Port 1:"), "loopMIDI Port 1:");
+        assert_eq!(device_name("Port 1:a"), "Port 1:a");
+        assert_eq!(device_name("Keystation"), "Keystation");
     }
 }
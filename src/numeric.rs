@@ -10,12 +10,6 @@ pub trait Numeric: Clone + Copy + PartialEq + PartialOrd + 'static {
     /// Is this a duration?
     const DURATION: bool;
 
-    /// Smallest finite value
-    const MIN: Self;
-
-    /// Largest finite value
-    const MAX: Self;
-
     fn to_f64(self) -> f64;
 
     fn from_f64(num: f64) -> Self;
@@ -25,10 +19,6 @@ impl Numeric for Duration {
     const INTEGRAL: bool = false;
     const DURATION: bool = true;
 
-    const MIN: Self = Duration::ZERO;
-
-    const MAX: Self = Duration::MAX;
-
     #[inline]
     fn to_f64(self) -> f64 {
         self.as_secs_f64()
@@ -45,8 +35,6 @@ macro_rules! impl_from_emath {
         impl Numeric for $t {
             const INTEGRAL: bool = <Self as emath::Numeric>::INTEGRAL;
             const DURATION: bool = false;
-            const MIN: Self = <Self as emath::Numeric>::MIN;
-            const MAX: Self = <Self as emath::Numeric>::MAX;
 
             #[inline]
             fn to_f64(self) -> f64 {
@@ -0,0 +1,862 @@
+//! Open Sound Control over UDP, for sequencers and scripts that would rather not speak MIDI.
+//!
+//! The emitter listens under `/nebulizer/1/`:
+//! - `note_on <key> [velocity] [channel]` and `note_off <key> [channel]` play notes
+//! - `<param> <value>` sets a parameter, e.g. `/nebulizer/1/density 20.0`. Parameter names are
+//!   the `ControlParam`s in snake case, values are in the parameter's own unit (Hz, seconds,
+//!   BPM, ...) and clamped to its range.
+//! - `<param>` without a value asks for the current value, which is sent back to the asking
+//!   address as `<param> <value>`
+//!
+//! SuperDirt's `/dirt/play` is understood too, so that TidalCycles can play the emitter as a
+//! sound, named by `s` ([`DEFAULT_DIRT_SOUND`] unless chosen otherwise). `n` and `note` pick
+//! the key (or slice), `legato` or `sustain` how long it's held, `speed`, `gain` and `pan` shape
+//! its grains and `begin`/`end` the part of the sample they're taken from. Without a length,
+//! notes go through the envelope's attack, decay and release. Grains can't play backwards, so a
+//! negative `speed` plays forwards at the same rate.
+//!
+//! Messages in bundles wait for the bundle's time tag, which Tidal sets a little ahead.
+
+use std::{
+    io, mem,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    emitter::{EmitterMessage, OneShot},
+    params::{ControlParam, EmitterParams, ParamSync, SharedParams},
+};
+use midly::num::{u4, u7};
+
+/// Prefix of every address the emitter responds to
+const ADDRESS_PREFIX: &str = "/nebulizer/1/";
+
+/// How long the server waits for a packet before checking whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sound that `/dirt/play` events need to name in `s` to be played, unless chosen otherwise
+pub const DEFAULT_DIRT_SOUND: &str = "nebulizer";
+
+/// Longest note that a `/dirt/play` event can hold, in seconds
+const MAX_DIRT_SUSTAIN: f64 = 60.0;
+
+/// Largest packet that fits into a UDP datagram
+const MAX_PACKET_SIZE: usize = 65536;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Long(i64),
+    Double(f64),
+    String(String),
+    Blob(Vec<u8>),
+    Bool(bool),
+    Nil,
+}
+
+impl OscArg {
+    /// Numeric value of the argument, if it has a finite one
+    pub fn as_f64(&self) -> Option<f64> {
+        let value = match self {
+            OscArg::Int(i) => *i as f64,
+            OscArg::Float(f) => *f as f64,
+            OscArg::Long(i) => *i as f64,
+            OscArg::Double(d) => *d,
+            OscArg::Bool(b) => *b as u8 as f64,
+            _ => return None,
+        };
+        value.is_finite().then_some(value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    /// Encode the message as an OSC packet
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::new();
+        write_string(&mut packet, &self.address);
+
+        let mut tags = String::from(",");
+        for arg in &self.args {
+            tags.push(match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::Long(_) => 'h',
+                OscArg::Double(_) => 'd',
+                OscArg::String(_) => 's',
+                OscArg::Blob(_) => 'b',
+                OscArg::Bool(true) => 'T',
+                OscArg::Bool(false) => 'F',
+                OscArg::Nil => 'N',
+            });
+        }
+        write_string(&mut packet, &tags);
+
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(i) => packet.extend(i.to_be_bytes()),
+                OscArg::Float(f) => packet.extend(f.to_be_bytes()),
+                OscArg::Long(i) => packet.extend(i.to_be_bytes()),
+                OscArg::Double(d) => packet.extend(d.to_be_bytes()),
+                OscArg::String(s) => write_string(&mut packet, s),
+                OscArg::Blob(blob) => {
+                    packet.extend((blob.len() as i32).to_be_bytes());
+                    packet.extend(blob);
+                    pad(&mut packet);
+                }
+                OscArg::Bool(_) | OscArg::Nil => {}
+            }
+        }
+        packet
+    }
+}
+
+fn write_string(packet: &mut Vec<u8>, s: &str) {
+    packet.extend(s.as_bytes());
+    packet.push(0);
+    pad(packet);
+}
+
+/// Fill up with zeros to the next multiple of four bytes
+fn pad(packet: &mut Vec<u8>) {
+    while packet.len() % 4 != 0 {
+        packet.push(0);
+    }
+}
+
+/// Reads the parts of a packet in order
+struct PacketReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> PacketReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        if len > self.data.len() {
+            return Err("packet ends early");
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], &'static str> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn string(&mut self) -> Result<&'a str, &'static str> {
+        let len = self
+            .data
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or("unterminated string")?;
+        let bytes = self.take(len)?;
+        // the terminator and padding up to a multiple of four
+        self.take(4 - len % 4)?;
+        std::str::from_utf8(bytes).map_err(|_| "string isn't UTF-8")
+    }
+
+    fn arg(&mut self, tag: char) -> Result<OscArg, &'static str> {
+        let arg = match tag {
+            'i' => OscArg::Int(i32::from_be_bytes(self.take_array()?)),
+            'f' => OscArg::Float(f32::from_be_bytes(self.take_array()?)),
+            'h' => OscArg::Long(i64::from_be_bytes(self.take_array()?)),
+            'd' => OscArg::Double(f64::from_be_bytes(self.take_array()?)),
+            's' | 'S' => OscArg::String(self.string()?.to_string()),
+            'b' => {
+                let len = i32::from_be_bytes(self.take_array()?);
+                let len = usize::try_from(len).map_err(|_| "negative blob size")?;
+                let blob = self.take(len)?.to_vec();
+                self.take((4 - len % 4) % 4)?;
+                OscArg::Blob(blob)
+            }
+            // only the time tags of bundles schedule messages, arguments are just numbers
+            't' => OscArg::Long(i64::from_be_bytes(self.take_array()?)),
+            'T' => OscArg::Bool(true),
+            'F' => OscArg::Bool(false),
+            'N' | 'I' => OscArg::Nil,
+            _ => return Err("unsupported argument type"),
+        };
+        Ok(arg)
+    }
+}
+
+/// Decode a packet into the messages it contains, along with the time they should take effect
+/// at if they came in a bundle that isn't due immediately
+pub fn decode_packet(
+    data: &[u8],
+    time: Option<SystemTime>,
+    messages: &mut Vec<(Option<SystemTime>, OscMessage)>,
+) -> Result<(), &'static str> {
+    let mut reader = PacketReader { data };
+
+    if data.starts_with(b"#bundle\0") {
+        reader.take(8)?;
+        let time = time_tag_to_system_time(u64::from_be_bytes(reader.take_array()?));
+        while !reader.data.is_empty() {
+            let len = i32::from_be_bytes(reader.take_array()?);
+            let len = usize::try_from(len).map_err(|_| "negative bundle element size")?;
+            decode_packet(reader.take(len)?, time, messages)?;
+        }
+        return Ok(());
+    }
+
+    let address = reader.string()?;
+    if !address.starts_with('/') {
+        return Err("address doesn't start with /");
+    }
+
+    // some old implementations leave out the type tags when there are no arguments
+    let tags = if reader.data.is_empty() {
+        ","
+    } else {
+        reader.string()?
+    };
+    let tags = tags.strip_prefix(',').ok_or("missing type tags")?;
+
+    let args = tags
+        .chars()
+        // arrays are flattened
+        .filter(|tag| !matches!(tag, '[' | ']'))
+        .map(|tag| reader.arg(tag))
+        .collect::<Result<_, _>>()?;
+
+    let message = OscMessage {
+        address: address.to_string(),
+        args,
+    };
+    messages.push((time, message));
+    Ok(())
+}
+
+/// Seconds from the start of 1900, where OSC time tags count from, to the Unix epoch
+const UNIX_EPOCH_TIME_TAG: u64 = 2_208_988_800;
+
+/// Time that an OSC time tag stands for, `None` meaning immediately
+fn time_tag_to_system_time(tag: u64) -> Option<SystemTime> {
+    if tag == 1 {
+        return None;
+    }
+    let seconds = (tag >> 32).checked_sub(UNIX_EPOCH_TIME_TAG)?;
+    let nanos = ((tag & 0xffff_ffff) * 1_000_000_000) >> 32;
+    Some(UNIX_EPOCH + Duration::new(seconds, nanos as u32))
+}
+
+/// Changes made in the GUI that the OSC thread needs to know about
+pub enum OscUpdate {
+    /// A new emitter was created that should receive the notes from now on
+    Emitter(Sender<EmitterMessage>),
+    /// `/dirt/play` events of this sound are played from now on
+    DirtSound(String),
+}
+
+/// Listens for OSC messages on a UDP port until dropped
+pub struct OscServer {
+    pub port: u16,
+    updates: Sender<OscUpdate>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl OscServer {
+    /// Start listening on `port` of the local machine
+    pub fn start(port: u16, shared_params: Arc<SharedParams>) -> io::Result<OscServer> {
+        let socket = UdpSocket::bind(("127.0.0.1", port))?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        // port 0 picks any free port
+        let port = socket.local_addr()?.port();
+
+        let (tx, rx) = mpsc::channel();
+        let running = Arc::new(AtomicBool::new(true));
+        let mut handler = OscHandler::new(socket, rx, shared_params);
+        let thread = thread::Builder::new().name("osc".to_string()).spawn({
+            let running = running.clone();
+            move || {
+                while running.load(Ordering::Relaxed) {
+                    handler.receive();
+                }
+            }
+        })?;
+
+        Ok(OscServer {
+            port,
+            updates: tx,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn update(&self, update: OscUpdate) {
+        let _ = self.updates.send(update);
+    }
+}
+
+impl Drop for OscServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Turns incoming OSC messages into emitter messages and parameter changes, on its own thread
+struct OscHandler {
+    socket: UdpSocket,
+    updates: Receiver<OscUpdate>,
+    emitter: Option<Sender<EmitterMessage>>,
+    shared_params: Arc<SharedParams>,
+    /// local copy of the parameters, only used for their continuous values
+    params: EmitterParams,
+    param_sync: ParamSync,
+    /// sound name of the `/dirt/play` events that are played, the others are for other synths
+    dirt_sound: String,
+    buffer: Vec<u8>,
+    messages: Vec<(Option<SystemTime>, OscMessage)>,
+    /// messages from bundles that aren't due yet, along with who sent them
+    scheduled: Vec<(SystemTime, OscMessage, SocketAddr)>,
+}
+
+impl OscHandler {
+    fn new(
+        socket: UdpSocket,
+        updates: Receiver<OscUpdate>,
+        shared_params: Arc<SharedParams>,
+    ) -> Self {
+        let mut params = EmitterParams::default();
+        let param_sync = ParamSync::new(&shared_params, &mut params);
+        Self {
+            socket,
+            updates,
+            emitter: None,
+            shared_params,
+            params,
+            param_sync,
+            dirt_sound: DEFAULT_DIRT_SOUND.to_string(),
+            buffer: vec![0; MAX_PACKET_SIZE],
+            messages: Vec::new(),
+            scheduled: Vec::new(),
+        }
+    }
+
+    /// Wait for a packet and handle the messages in it, along with scheduled messages that
+    /// became due in the meantime
+    fn receive(&mut self) {
+        // wake up in time for the next scheduled message
+        let now = SystemTime::now();
+        let timeout = self
+            .scheduled
+            .iter()
+            .map(|(time, ..)| time.duration_since(now).unwrap_or(Duration::ZERO))
+            .fold(POLL_INTERVAL, Duration::min)
+            // a zero timeout isn't allowed
+            .max(Duration::from_millis(1));
+        let _ = self.socket.set_read_timeout(Some(timeout));
+
+        let received = self.socket.recv_from(&mut self.buffer);
+
+        while let Ok(update) = self.updates.try_recv() {
+            match update {
+                OscUpdate::Emitter(sender) => self.emitter = Some(sender),
+                OscUpdate::DirtSound(sound) => self.dirt_sound = sound,
+            }
+        }
+
+        match received {
+            Ok((len, sender)) => self.handle_packet(len, sender),
+            // timeouts show up as either, depending on the platform
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(err) => eprintln!("Can't receive OSC: {err}"),
+        }
+
+        self.run_scheduled();
+    }
+
+    fn handle_packet(&mut self, len: usize, sender: SocketAddr) {
+        self.messages.clear();
+        if let Err(err) = decode_packet(&self.buffer[..len], None, &mut self.messages) {
+            eprintln!("Skipping OSC packet from {sender}: {err}");
+            return;
+        }
+
+        let now = SystemTime::now();
+        for (time, message) in mem::take(&mut self.messages) {
+            match time {
+                Some(time) if time > now => self.scheduled.push((time, message, sender)),
+                _ => self.dispatch(&message, sender),
+            }
+        }
+    }
+
+    /// Handle scheduled messages that are due, in order
+    fn run_scheduled(&mut self) {
+        let now = SystemTime::now();
+        self.scheduled.sort_by_key(|(time, ..)| *time);
+        let due = self
+            .scheduled
+            .iter()
+            .take_while(|(time, ..)| *time <= now)
+            .count();
+        let due: Vec<_> = self.scheduled.drain(..due).collect();
+        for (_, message, sender) in due {
+            self.dispatch(&message, sender);
+        }
+    }
+
+    fn dispatch(&mut self, message: &OscMessage, sender: SocketAddr) {
+        if !self.handle_message(message, sender) {
+            eprintln!("Skipping OSC message {message:?}");
+        }
+    }
+
+    /// Returns whether the message was understood
+    fn handle_message(&mut self, message: &OscMessage, sender: SocketAddr) -> bool {
+        if message.address == "/dirt/play" {
+            let sound = match dirt_arg(&message.args, "s") {
+                Some(OscArg::String(sound)) => sound,
+                _ => return false,
+            };
+            if *sound == self.dirt_sound {
+                self.send(EmitterMessage::OneShot(dirt_event(&message.args)));
+            }
+            return true;
+        }
+
+        let Some(name) = message.address.strip_prefix(ADDRESS_PREFIX) else {
+            return false;
+        };
+        let numbers: Option<Vec<f64>> = message.args.iter().map(OscArg::as_f64).collect();
+        let Some(numbers) = numbers else {
+            return false;
+        };
+
+        match name {
+            "note_on" => {
+                let Some(&key) = numbers.first() else {
+                    return false;
+                };
+                let vel = numbers.get(1).copied().unwrap_or(100.0);
+                let channel = numbers.get(2).copied().unwrap_or(0.0);
+                self.send(EmitterMessage::NoteOn {
+                    channel: u4::from(channel.clamp(0.0, 15.0) as u8),
+                    key: u7::from(key.clamp(0.0, 127.0) as u8),
+                    vel: u7::from(vel.clamp(0.0, 127.0) as u8),
+                });
+                true
+            }
+            "note_off" => {
+                let Some(&key) = numbers.first() else {
+                    return false;
+                };
+                let channel = numbers.get(1).copied().unwrap_or(0.0);
+                self.send(EmitterMessage::NoteOff {
+                    channel: u4::from(channel.clamp(0.0, 15.0) as u8),
+                    key: u7::from(key.clamp(0.0, 127.0) as u8),
+                });
+                true
+            }
+            _ => {
+                let Some(param) = ControlParam::from_snake_case_name(name) else {
+                    return false;
+                };
+
+                self.param_sync.pull(&self.shared_params, &mut self.params);
+                match numbers.first() {
+                    Some(&value) => {
+                        self.params.control_mut(param).set_clamped(value);
+                        self.param_sync.push(&self.shared_params, &self.params);
+                    }
+                    None => {
+                        let reply = OscMessage {
+                            address: message.address.clone(),
+                            args: vec![OscArg::Float(self.params.control(param).value() as f32)],
+                        };
+                        let _ = self.socket.send_to(&reply.encode(), sender);
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    fn send(&self, msg: EmitterMessage) {
+        if let Some(emitter) = &self.emitter {
+            let _ = emitter.send(msg);
+        }
+    }
+}
+
+/// Value of a SuperDirt `/dirt/play` event's argument, as they come in pairs of a name and a value
+fn dirt_arg<'a>(args: &'a [OscArg], name: &str) -> Option<&'a OscArg> {
+    args.chunks_exact(2).find_map(|pair| match &pair[0] {
+        OscArg::String(key) if key == name => Some(&pair[1]),
+        _ => None,
+    })
+}
+
+/// One-shot note for a SuperDirt `/dirt/play` event
+fn dirt_event(args: &[OscArg]) -> OneShot {
+    let value = |name: &str| dirt_arg(args, name).and_then(OscArg::as_f64);
+
+    let length = match (value("sustain"), value("legato"), value("delta")) {
+        (Some(sustain), _, _) => Some(sustain),
+        (None, Some(legato), Some(delta)) => Some(legato * delta),
+        _ => None,
+    };
+
+    OneShot {
+        n: (value("n").unwrap_or(0.0) + value("note").unwrap_or(0.0)) as f32,
+        length: length.map(|length| Duration::from_secs_f64(length.clamp(0.0, MAX_DIRT_SUSTAIN))),
+        // grains can't play backwards
+        speed: value("speed").unwrap_or(1.0).abs().clamp(1.0 / 16.0, 16.0) as f32,
+        // like SuperDirt, where a gain of 1 is unity and a little more gets loud quickly
+        gain: value("gain").unwrap_or(1.0).clamp(0.0, 2.0).powi(4) as f32,
+        pan: value("pan").unwrap_or(0.5).clamp(0.0, 1.0) as f32,
+        begin: value("begin").map(|begin| begin.clamp(0.0, 1.0) as f32),
+        end: value("end").map(|end| end.clamp(0.0, 1.0) as f32),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Messages of a packet, with whether each of them was scheduled
+    fn decode(data: &[u8]) -> Result<Vec<(bool, OscMessage)>, &'static str> {
+        let mut messages = Vec::new();
+        decode_packet(data, None, &mut messages)?;
+        Ok(messages
+            .into_iter()
+            .map(|(time, message)| (time.is_some(), message))
+            .collect())
+    }
+
+    fn message(address: &str, args: Vec<OscArg>) -> OscMessage {
+        OscMessage {
+            address: address.to_string(),
+            args,
+        }
+    }
+
+    /// Bundle of encoded messages with a time tag
+    fn bundle(time_tag: u64, elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut packet = b"#bundle\0".to_vec();
+        packet.extend(time_tag.to_be_bytes());
+        for element in elements {
+            packet.extend((element.len() as i32).to_be_bytes());
+            packet.extend(element);
+        }
+        packet
+    }
+
+    #[test]
+    fn messages_decode_to_themselves() {
+        let sent = message(
+            "/nebulizer/1/test",
+            vec![
+                OscArg::Int(-3),
+                OscArg::Float(0.5),
+                OscArg::Long(1 << 40),
+                OscArg::Double(0.25),
+                OscArg::String("abc".to_string()),
+                OscArg::Blob(vec![1, 2, 3, 4, 5]),
+                OscArg::Bool(true),
+                OscArg::Bool(false),
+                OscArg::Nil,
+            ],
+        );
+        assert_eq!(decode(&sent.encode()), Ok(vec![(false, sent)]));
+    }
+
+    #[test]
+    fn strings_and_blobs_are_padded_to_four_bytes() {
+        // with their terminator, strings of 3 bytes fill exactly 4 and strings of 4 need 8
+        for (address, len) in [("/ab", 4), ("/abc", 8), ("/abcdefg", 12)] {
+            let packet = message(address, vec![]).encode();
+            assert_eq!(packet.len(), len + 4, "{address}");
+            assert_eq!(decode(&packet).unwrap()[0].1.address, address);
+        }
+
+        let blob = message("/b", vec![OscArg::Blob(vec![7; 5]), OscArg::Int(9)]);
+        let packet = blob.encode();
+        // address, tags, blob size, 5 bytes padded to 8, int
+        assert_eq!(packet.len(), 4 + 4 + 4 + 8 + 4);
+        assert_eq!(decode(&packet), Ok(vec![(false, blob)]));
+    }
+
+    #[test]
+    fn type_tags_may_be_left_out_without_arguments() {
+        let packet = b"/ping\0\0\0";
+        assert_eq!(decode(packet), Ok(vec![(false, message("/ping", vec![]))]));
+    }
+
+    #[test]
+    fn bundles_are_unpacked_with_their_time() {
+        let a = message("/a", vec![OscArg::Int(1)]);
+        let b = message("/b", vec![OscArg::Float(2.0)]);
+        let c = message("/c", vec![]);
+
+        // a time tag of 1 means immediately
+        let now = bundle(1, &[a.encode(), b.encode()]);
+        assert_eq!(
+            decode(&now),
+            Ok(vec![(false, a.clone()), (false, b.clone())])
+        );
+
+        let later = (UNIX_EPOCH_TIME_TAG + 2_000_000_000) << 32;
+        let nested = bundle(later, &[a.encode(), bundle(1, &[b.encode()]), c.encode()]);
+        let decoded = decode(&nested).unwrap();
+        assert_eq!(decoded, [(true, a), (false, b), (true, c)]);
+
+        let mut messages = Vec::new();
+        decode_packet(&nested, None, &mut messages).unwrap();
+        let expected = UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+        assert_eq!(messages[0].0, Some(expected));
+    }
+
+    #[test]
+    fn truncated_packets_are_rejected() {
+        let packet = message(
+            "/nebulizer/1/note_on",
+            vec![OscArg::Int(60), OscArg::String("text".to_string())],
+        )
+        .encode();
+        // the padded address alone is a message whose type tags were left out
+        let address_end = 24;
+        for len in (0..packet.len()).filter(|len| *len != address_end) {
+            assert!(decode(&packet[..len]).is_err(), "{len} bytes were accepted");
+        }
+
+        let element = message("/a", vec![OscArg::Int(1)]).encode();
+        let packet = bundle(1, &[element]);
+        // after the header and time tag, the bundle is merely empty
+        for len in (8..packet.len()).filter(|len| *len != 16) {
+            assert!(
+                decode(&packet[..len]).is_err(),
+                "{len} bundle bytes were accepted"
+            );
+        }
+
+        let mut negative_blob = message("/b", vec![OscArg::Blob(vec![])]).encode();
+        let size = negative_blob.len() - 4;
+        negative_blob[size..].copy_from_slice(&(-4i32).to_be_bytes());
+        assert!(decode(&negative_blob).is_err());
+    }
+
+    /// Server on a free port that sends to the returned emitter channel, and a client socket
+    fn start_server() -> (
+        OscServer,
+        Arc<SharedParams>,
+        Receiver<EmitterMessage>,
+        UdpSocket,
+    ) {
+        let shared_params = Arc::new(SharedParams::new(&EmitterParams::default()));
+        let server = OscServer::start(0, shared_params.clone()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        server.update(OscUpdate::Emitter(tx));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(("127.0.0.1", server.port)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (server, shared_params, rx, client)
+    }
+
+    /// Send a message and wait until the server handled it, by asking for a value afterwards
+    fn send_and_wait(client: &UdpSocket, sent: OscMessage) {
+        client.send(&sent.encode()).unwrap();
+        let query = message("/nebulizer/1/density", vec![]);
+        client.send(&query.encode()).unwrap();
+        let mut buffer = [0; 1024];
+        client.recv(&mut buffer).unwrap();
+    }
+
+    #[test]
+    fn server_plays_notes() {
+        let (_server, _, rx, client) = start_server();
+        let note_on = message(
+            "/nebulizer/1/note_on",
+            vec![OscArg::Int(64), OscArg::Float(90.0), OscArg::Int(2)],
+        );
+        client.send(&note_on.encode()).unwrap();
+
+        let received = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(
+            matches!(
+                received,
+                EmitterMessage::NoteOn { channel, key, vel }
+                    if (channel.as_int(), key.as_int(), vel.as_int()) == (2, 64, 90)
+            ),
+            "received something other than the note"
+        );
+    }
+
+    /// `/dirt/play` event of a sound
+    /// Arguments of a `/dirt/play` event, as pairs of names and values
+    fn dirt_args(args: Vec<(&str, OscArg)>) -> Vec<OscArg> {
+        args.into_iter()
+            .flat_map(|(name, value)| [OscArg::String(name.to_string()), value])
+            .collect()
+    }
+
+    fn dirt_play(sound: &str, n: f32) -> OscMessage {
+        let args = dirt_args(vec![
+            ("s", OscArg::String(sound.to_string())),
+            ("n", OscArg::Float(n)),
+        ]);
+        message("/dirt/play", args)
+    }
+
+    /// `n` of the one-shot notes that the server played
+    fn played_oneshots(rx: &Receiver<EmitterMessage>) -> Vec<f32> {
+        rx.try_iter()
+            .filter_map(|message| match message {
+                EmitterMessage::OneShot(oneshot) => Some(oneshot.n),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dirt_events_default_to_a_plain_note() {
+        let oneshot = dirt_event(&dirt_args(vec![(
+            "s",
+            OscArg::String("nebulizer".to_string()),
+        )]));
+        assert_eq!(oneshot.n, 0.0);
+        assert_eq!(oneshot.length, None);
+        assert_eq!((oneshot.speed, oneshot.gain, oneshot.pan), (1.0, 1.0, 0.5));
+        assert_eq!((oneshot.begin, oneshot.end), (None, None));
+    }
+
+    #[test]
+    fn dirt_events_pick_the_key_with_n_and_note() {
+        let oneshot = dirt_event(&dirt_args(vec![
+            ("n", OscArg::Float(3.5)),
+            ("note", OscArg::Int(12)),
+        ]));
+        assert_eq!(oneshot.n, 15.5);
+    }
+
+    #[test]
+    fn dirt_events_are_held_for_their_sustain_or_legato() {
+        let length = |args| dirt_event(&dirt_args(args)).length;
+        // legato is relative to the event's duration in seconds, delta
+        assert_eq!(
+            length(vec![
+                ("legato", OscArg::Float(2.0)),
+                ("delta", OscArg::Float(0.25))
+            ]),
+            Some(Duration::from_secs_f64(0.5))
+        );
+        assert_eq!(
+            length(vec![
+                ("sustain", OscArg::Float(0.125)),
+                ("legato", OscArg::Float(2.0))
+            ]),
+            Some(Duration::from_millis(125))
+        );
+        // the cycles per second alone don't tell how long the event is
+        assert_eq!(
+            length(vec![
+                ("legato", OscArg::Float(2.0)),
+                ("cps", OscArg::Float(0.5))
+            ]),
+            None
+        );
+        assert_eq!(
+            length(vec![
+                ("sustain", OscArg::Double(1e6)),
+                ("cps", OscArg::Float(0.5))
+            ]),
+            Some(Duration::from_secs_f64(MAX_DIRT_SUSTAIN))
+        );
+        assert_eq!(
+            length(vec![
+                ("sustain", OscArg::Float(-1.0)),
+                ("cps", OscArg::Float(0.5))
+            ]),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn dirt_events_shape_their_grains() {
+        let oneshot = |args| dirt_event(&dirt_args(args));
+
+        // grains only play forwards, so reversed speeds play at the same rate
+        assert_eq!(oneshot(vec![("speed", OscArg::Float(-2.0))]).speed, 2.0);
+        assert_eq!(
+            oneshot(vec![("speed", OscArg::Float(0.0))]).speed,
+            1.0 / 16.0
+        );
+        assert_eq!(oneshot(vec![("speed", OscArg::Float(100.0))]).speed, 16.0);
+
+        assert_eq!(oneshot(vec![("gain", OscArg::Float(0.5))]).gain, 0.0625);
+        assert_eq!(oneshot(vec![("gain", OscArg::Float(3.0))]).gain, 16.0);
+        assert_eq!(oneshot(vec![("gain", OscArg::Float(-1.0))]).gain, 0.0);
+
+        assert_eq!(oneshot(vec![("pan", OscArg::Float(0.25))]).pan, 0.25);
+        assert_eq!(oneshot(vec![("pan", OscArg::Float(-1.0))]).pan, 0.0);
+
+        let part = oneshot(vec![
+            ("begin", OscArg::Float(0.25)),
+            ("end", OscArg::Float(1.5)),
+        ]);
+        assert_eq!((part.begin, part.end), (Some(0.25), Some(1.0)));
+    }
+
+    #[test]
+    fn server_plays_only_its_own_dirt_sound() {
+        let (server, _, rx, client) = start_server();
+        send_and_wait(&client, dirt_play(DEFAULT_DIRT_SOUND, 1.0));
+        send_and_wait(&client, dirt_play("superpiano", 2.0));
+        assert_eq!(played_oneshots(&rx), [1.0]);
+
+        server.update(OscUpdate::DirtSound("superpiano".to_string()));
+        send_and_wait(&client, dirt_play(DEFAULT_DIRT_SOUND, 3.0));
+        send_and_wait(&client, dirt_play("superpiano", 4.0));
+        assert_eq!(played_oneshots(&rx), [4.0]);
+    }
+
+    #[test]
+    fn server_sets_and_reports_parameters() {
+        let (_server, shared_params, _rx, client) = start_server();
+        let set = message("/nebulizer/1/density", vec![OscArg::Double(20.0)]);
+        send_and_wait(&client, set);
+
+        let mut params = EmitterParams::default();
+        ParamSync::new(&shared_params, &mut params);
+        assert_eq!(params.density.get(), 20.0);
+
+        let query = message("/nebulizer/1/density", vec![]);
+        client.send(&query.encode()).unwrap();
+        let mut buffer = [0; 1024];
+        let len = client.recv(&mut buffer).unwrap();
+        let reply = message("/nebulizer/1/density", vec![OscArg::Float(20.0)]);
+        assert_eq!(decode(&buffer[..len]), Ok(vec![(false, reply)]));
+
+        // out of range values are clamped
+        let set = message("/nebulizer/1/density", vec![OscArg::Int(1000)]);
+        send_and_wait(&client, set);
+        ParamSync::new(&shared_params, &mut params);
+        assert_eq!(params.density.get(), 100.0);
+    }
+}
@@ -1,15 +1,23 @@
-use std::{ops::RangeInclusive, time::Duration};
+use std::{
+    fmt,
+    ops::RangeInclusive,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 use eframe::egui::{lerp, remap_clamp};
 use midly::num::u7;
+use strum::VariantArray;
 use strum_macros::{Display, VariantArray};
 
 use crate::{
     envelope::{AdsrEnvelope, GrainEnvelope},
     numeric::Numeric,
+    smoothing::SmoothingMode,
+    tempo::{NoteDivision, NoteModifier, NoteValue, TempoSource},
 };
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct Parameter<I> {
     value: I,
     range: RangeInclusive<I>,
@@ -45,11 +53,6 @@ where
         self
     }
 
-    pub fn smallest_positive(mut self, smallest_positive: f64) -> Self {
-        self.smallest_positive = smallest_positive;
-        self
-    }
-
     pub fn get(&self) -> I {
         self.value
     }
@@ -138,13 +141,60 @@ where
     }
 }
 
+/// Access to a parameter without knowing its numeric type
+pub trait DynParameter {
+    fn value(&self) -> f64;
+
+    fn set_value(&mut self, value: f64);
+
+    /// Set a value, keeping it within the parameter's range
+    fn set_clamped(&mut self, value: f64);
+
+    fn get_normalized(&self) -> f64;
+
+    fn set_normalized(&mut self, norm_val: f64);
+}
+
+impl<I> DynParameter for Parameter<I>
+where
+    I: Numeric,
+{
+    fn value(&self) -> f64 {
+        self.value.to_f64()
+    }
+
+    fn set_value(&mut self, value: f64) {
+        self.value = I::from_f64(value);
+    }
+
+    fn set_clamped(&mut self, value: f64) {
+        let (start, end) = (self.range.start().to_f64(), self.range.end().to_f64());
+        self.value = I::from_f64(value.clamp(start.min(end), start.max(end)));
+    }
+
+    fn get_normalized(&self) -> f64 {
+        Parameter::get_normalized(self)
+    }
+
+    fn set_normalized(&mut self, norm_val: f64) {
+        Parameter::set_normalized(self, norm_val)
+    }
+}
+
 #[derive(Clone, PartialEq, Eq)]
 pub enum KeyMode {
     Pitch,
     Slice,
 }
 
-#[derive(Clone)]
+/// Upper limit for `EmitterParams::polyphony`, voices are preallocated for this many notes
+pub const MAX_POLYPHONY: u32 = 64;
+
+/// Upper limit for `EmitterParams::max_grains`, and size of the preallocated grain pool.
+/// Fits a full cloud at maximum density, grain length and polyphony.
+pub const MAX_GRAINS: u32 = 8192;
+
+#[derive(Clone, PartialEq)]
 pub struct EmitterParams {
     pub midi_cc_map: MidiControlMap,
 
@@ -166,6 +216,39 @@ pub struct EmitterParams {
     /// The number of grains played per second (in hz)
     pub density: Parameter<f32>,
 
+    /// Whether the grain rate is set by `density` or follows the frequency of each note
+    pub density_mode: DensityMode,
+
+    /// Grain rate relative to the note frequency when density follows the key
+    pub density_ratio: Parameter<f32>,
+
+    /// Time between grains when density is synced to the tempo
+    pub density_division: NoteDivision,
+
+    /// Use `length_division` instead of `length`
+    pub length_synced: bool,
+
+    /// Grain length when it is synced to the tempo
+    pub length_division: NoteDivision,
+
+    /// Tempo in beats per minute that synced densities and lengths follow
+    pub bpm: Parameter<f32>,
+
+    /// Where the tempo comes from
+    pub tempo_source: TempoSource,
+
+    /// Release all notes when a MIDI Stop message is received
+    pub stop_releases_notes: bool,
+
+    /// Delay of every second grain when density is synced, from straight (0) to shuffled (1)
+    pub swing: Parameter<f32>,
+
+    /// How much the time between grains varies randomly, from periodic (0) to asynchronous (1)
+    pub jitter: Parameter<f32>,
+
+    /// Distribution of the random variation in time between grains
+    pub jitter_distribution: JitterDistribution,
+
     /// Envelope applied to each grain
     pub grain_envelope: GrainEnvelope,
 
@@ -175,11 +258,105 @@ pub struct EmitterParams {
     // Number of notes that can be played simultaneously
     pub polyphony: u32,
 
+    /// Which note makes room when a new note would exceed the polyphony
+    pub voice_stealing: VoiceStealing,
+
+    /// Let grains of a stolen note play to their end instead of fading them out
+    pub stolen_grains_ring_out: bool,
+
+    /// Number of grains that can play simultaneously
+    pub max_grains: u32,
+
+    /// What happens when a new grain would exceed `max_grains`
+    pub grain_culling: GrainCulling,
+
+    /// Whether notes play independently or all share a single voice
+    pub voice_mode: VoiceMode,
+
+    /// Which of the held keys is sounding in mono mode
+    pub note_priority: NotePriority,
+
+    /// Don't retrigger the note envelope when moving between held keys in mono mode
+    pub legato: bool,
+
+    /// Time taken to slide the pitch (or slice position) between notes in mono mode
+    pub glide: Parameter<Duration>,
+
     /// Pitch transposition of input sample in semitones
     pub transpose: Parameter<i32>,
 
     /// The volume level of sound coming out of the emitter, relative to the original audio sample
     pub amplitude: Parameter<f32>,
+
+    /// How per-note expression affects grains when playing with MPE
+    pub mpe: MpeParams,
+
+    /// Time taken by continuous parameters to catch up with a new value
+    pub smoothing: Parameter<Duration>,
+
+    /// Shape of the transition between old and new parameter values
+    pub smoothing_mode: SmoothingMode,
+}
+
+impl EmitterParams {
+    /// The parameter that a `ControlParam` refers to
+    pub fn control(&self, param: &ControlParam) -> &dyn DynParameter {
+        match param {
+            ControlParam::Position => &self.position,
+            ControlParam::NumSlices => &self.num_slices,
+            ControlParam::Spray => &self.spray,
+            ControlParam::Length => &self.length,
+            ControlParam::Density => &self.density,
+            ControlParam::DensityRatio => &self.density_ratio,
+            ControlParam::Jitter => &self.jitter,
+            ControlParam::GrainEnvelopeAmount => &self.grain_envelope.amount,
+            ControlParam::GrainEnvelopeSkew => &self.grain_envelope.skew,
+            ControlParam::NoteEnvelopeAttack => &self.note_envelope.attack,
+            ControlParam::NoteEnvelopeDecay => &self.note_envelope.decay,
+            ControlParam::NoteEnvelopeSustain => &self.note_envelope.sustain_level,
+            ControlParam::NoteEnvelopeRelease => &self.note_envelope.release,
+            ControlParam::Transpose => &self.transpose,
+            ControlParam::Amplitude => &self.amplitude,
+            ControlParam::Glide => &self.glide,
+            ControlParam::Tempo => &self.bpm,
+        }
+    }
+
+    /// Mutable access to the parameter that a `ControlParam` refers to
+    pub fn control_mut(&mut self, param: &ControlParam) -> &mut dyn DynParameter {
+        match param {
+            ControlParam::Position => &mut self.position,
+            ControlParam::NumSlices => &mut self.num_slices,
+            ControlParam::Spray => &mut self.spray,
+            ControlParam::Length => &mut self.length,
+            ControlParam::Density => &mut self.density,
+            ControlParam::DensityRatio => &mut self.density_ratio,
+            ControlParam::Jitter => &mut self.jitter,
+            ControlParam::GrainEnvelopeAmount => &mut self.grain_envelope.amount,
+            ControlParam::GrainEnvelopeSkew => &mut self.grain_envelope.skew,
+            ControlParam::NoteEnvelopeAttack => &mut self.note_envelope.attack,
+            ControlParam::NoteEnvelopeDecay => &mut self.note_envelope.decay,
+            ControlParam::NoteEnvelopeSustain => &mut self.note_envelope.sustain_level,
+            ControlParam::NoteEnvelopeRelease => &mut self.note_envelope.release,
+            ControlParam::Transpose => &mut self.transpose,
+            ControlParam::Amplitude => &mut self.amplitude,
+            ControlParam::Glide => &mut self.glide,
+            ControlParam::Tempo => &mut self.bpm,
+        }
+    }
+}
+
+impl EmitterParams {
+    /// Whether everything apart from the values of `ControlParam`s is the same
+    pub fn same_settings(&self, other: &EmitterParams) -> bool {
+        let mut a = self.clone();
+        let mut b = other.clone();
+        for param in ControlParam::VARIANTS {
+            a.control_mut(param).set_value(0.0);
+            b.control_mut(param).set_value(0.0);
+        }
+        a == b
+    }
 }
 
 impl Default for EmitterParams {
@@ -197,15 +374,130 @@ impl Default for EmitterParams {
             )
             .logarithmic(true),
             density: Parameter::new(10.0, 1.0..=100.0).logarithmic(true),
+            density_mode: DensityMode::Free,
+            density_ratio: Parameter::new(1.0, 0.125..=8.0).logarithmic(true),
+            density_division: NoteDivision::new(NoteValue::Sixteenth, NoteModifier::Straight),
+            length_synced: false,
+            length_division: NoteDivision::new(NoteValue::Sixteenth, NoteModifier::Straight),
+            bpm: Parameter::new(120.0, 20.0..=300.0),
+            tempo_source: TempoSource::Internal,
+            stop_releases_notes: true,
+            swing: Parameter::new(0.0, 0.0..=1.0),
+            jitter: Parameter::new(0.0, 0.0..=1.0),
+            jitter_distribution: JitterDistribution::Uniform,
             grain_envelope: GrainEnvelope::default(),
             note_envelope: AdsrEnvelope::default(),
             polyphony: 8,
+            voice_stealing: VoiceStealing::Oldest,
+            stolen_grains_ring_out: false,
+            max_grains: 2048,
+            grain_culling: GrainCulling::FadeOldest,
+            voice_mode: VoiceMode::Poly,
+            note_priority: NotePriority::Last,
+            legato: false,
+            glide: Parameter::new(Duration::ZERO, Duration::ZERO..=Duration::from_secs(2))
+                .logarithmic(true),
             transpose: Parameter::new(0, -12..=12),
             amplitude: Parameter::new(1.0, 0.0..=1.0),
+            mpe: MpeParams::default(),
+            smoothing: Parameter::new(
+                Duration::from_millis(20),
+                Duration::ZERO..=Duration::from_secs(1),
+            )
+            .logarithmic(true),
+            smoothing_mode: SmoothingMode::OnePole,
         }
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DensityMode {
+    /// Grains are played at the rate set by `density`
+    Free,
+    /// Grains are played at the frequency of the note, so that their onsets form a pitched pulse
+    /// train
+    KeyTracked,
+    /// Grains are played at a note division of the tempo
+    Synced,
+}
+
+#[derive(Clone, Copy, Display, VariantArray, PartialEq, Eq)]
+pub enum JitterDistribution {
+    /// Time between grains varies evenly around its average
+    Uniform,
+    /// Grains start independently of each other, like the clicks of a Geiger counter
+    Poisson,
+}
+
+#[derive(Clone, Copy, Display, VariantArray, PartialEq, Eq)]
+pub enum GrainCulling {
+    /// Don't play new grains until others have finished
+    #[strum(to_string = "Skip new")]
+    SkipNew,
+    /// Fade out the grain that is currently the quietest
+    #[strum(to_string = "Fade quietest")]
+    FadeQuietest,
+    /// Fade out the grain that has been playing the longest
+    #[strum(to_string = "Fade oldest")]
+    FadeOldest,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum VoiceMode {
+    Poly,
+    Mono,
+}
+
+#[derive(Clone, Copy, Display, VariantArray, PartialEq, Eq)]
+pub enum VoiceStealing {
+    Oldest,
+    Quietest,
+    #[strum(to_string = "Released first")]
+    ReleasedFirst,
+    /// Also retriggers a key that is still sounding, even below the polyphony limit
+    #[strum(to_string = "Same key")]
+    SameKey,
+}
+
+#[derive(Clone, Copy, Display, VariantArray, PartialEq, Eq)]
+pub enum NotePriority {
+    Last,
+    Low,
+    High,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct MpeParams {
+    /// Range of per-note pitch bend in semitones
+    pub pitch_bend_range: u8,
+
+    /// What per-note channel pressure modulates
+    pub pressure_target: ExpressionTarget,
+
+    /// What per-note timbre (CC 74) modulates
+    pub timbre_target: ExpressionTarget,
+}
+
+impl Default for MpeParams {
+    fn default() -> Self {
+        Self {
+            pitch_bend_range: 48,
+            pressure_target: ExpressionTarget::Amplitude,
+            timbre_target: ExpressionTarget::Position,
+        }
+    }
+}
+
+/// Grain properties that can be modulated by a single note's expression
+#[derive(Clone, Copy, Display, VariantArray, PartialEq)]
+pub enum ExpressionTarget {
+    None,
+    Amplitude,
+    Position,
+    Length,
+    Density,
+}
+
 /// All emitter parameters that can be controlled with MIDI CC messages
 #[derive(Clone, Display, VariantArray, PartialEq)]
 pub enum ControlParam {
@@ -214,6 +506,8 @@ pub enum ControlParam {
     Spray,
     Length,
     Density,
+    DensityRatio,
+    Jitter,
     GrainEnvelopeAmount,
     GrainEnvelopeSkew,
     NoteEnvelopeAttack,
@@ -222,6 +516,334 @@ pub enum ControlParam {
     NoteEnvelopeRelease,
     Transpose,
     Amplitude,
+    Glide,
+    Tempo,
+}
+
+impl ControlParam {
+    pub fn index(&self) -> usize {
+        self.clone() as usize
+    }
+
+    /// Name of the parameter in OSC addresses and control commands, e.g. `density_ratio`
+    pub fn snake_case_name(&self) -> String {
+        let mut name = String::new();
+        for (i, c) in self.to_string().chars().enumerate() {
+            if c.is_uppercase() && i > 0 {
+                name.push('_');
+            }
+            name.push(c.to_ascii_lowercase());
+        }
+        name
+    }
+
+    pub fn from_snake_case_name(name: &str) -> Option<&'static ControlParam> {
+        ControlParam::VARIANTS
+            .iter()
+            .find(|param| param.snake_case_name() == name)
+    }
+}
+
+pub const NUM_CONTROL_PARAMS: usize = ControlParam::VARIANTS.len();
+
+/// Current values of all `ControlParam`s that can be read and written from any thread without
+/// locking
+pub struct SharedParams {
+    values: [AtomicU64; NUM_CONTROL_PARAMS],
+}
+
+impl SharedParams {
+    pub fn new(params: &EmitterParams) -> Self {
+        Self {
+            values: std::array::from_fn(|i| {
+                AtomicU64::new(params.control(&ControlParam::VARIANTS[i]).value().to_bits())
+            }),
+        }
+    }
+
+    fn load(&self, param: &ControlParam) -> u64 {
+        self.values[param.index()].load(Ordering::Relaxed)
+    }
+
+    fn store(&self, param: &ControlParam, bits: u64) {
+        self.values[param.index()].store(bits, Ordering::Relaxed)
+    }
+}
+
+/// Keeps one thread's copy of the parameters in sync with the shared values, so that only
+/// values that actually changed are exchanged
+pub struct ParamSync {
+    /// shared values as they were last seen
+    seen: [u64; NUM_CONTROL_PARAMS],
+    /// local values right after the last exchange
+    local: [f64; NUM_CONTROL_PARAMS],
+}
+
+impl ParamSync {
+    pub fn new(shared: &SharedParams, params: &mut EmitterParams) -> Self {
+        let mut sync = Self {
+            seen: [0; NUM_CONTROL_PARAMS],
+            local: [0.0; NUM_CONTROL_PARAMS],
+        };
+        sync.resync(shared, params);
+        sync
+    }
+
+    /// Take over every shared value, e.g. after the local parameters were replaced
+    pub fn resync(&mut self, shared: &SharedParams, params: &mut EmitterParams) {
+        self.seen = [!0; NUM_CONTROL_PARAMS];
+        self.pull(shared, params);
+    }
+
+    /// Take over values that other threads changed since the last exchange
+    pub fn pull(&mut self, shared: &SharedParams, params: &mut EmitterParams) {
+        for param in ControlParam::VARIANTS {
+            let i = param.index();
+            let bits = shared.load(param);
+            if bits != self.seen[i] {
+                self.seen[i] = bits;
+                let local = params.control_mut(param);
+                local.set_value(f64::from_bits(bits));
+                self.local[i] = local.value();
+            }
+        }
+    }
+
+    /// Publish values that were changed locally since the last exchange
+    pub fn push(&mut self, shared: &SharedParams, params: &EmitterParams) {
+        for param in ControlParam::VARIANTS {
+            let i = param.index();
+            let value = params.control(param).value();
+            if value != self.local[i] {
+                self.local[i] = value;
+                self.seen[i] = value.to_bits();
+                shared.store(param, self.seen[i]);
+            }
+        }
+    }
+}
+
+/// Kinds of MIDI controller messages that can be mapped to parameters
+#[derive(Clone, Copy, Display, VariantArray, PartialEq, Eq)]
+pub enum ControlKind {
+    /// Single 7-bit control change
+    #[strum(to_string = "CC")]
+    Cc,
+    /// 14-bit control change split into an MSB (CC 0-31) and an LSB (CC 32-63)
+    #[strum(to_string = "CC 14-bit")]
+    Cc14,
+    /// Non-registered parameter number
+    #[strum(to_string = "NRPN")]
+    Nrpn,
+    /// Registered parameter number
+    #[strum(to_string = "RPN")]
+    Rpn,
+}
+
+impl ControlKind {
+    /// Range of controller (or parameter) numbers for this kind of control
+    pub fn numbers(&self) -> RangeInclusive<u16> {
+        match self {
+            ControlKind::Cc => 0..=127,
+            ControlKind::Cc14 => 0..=31,
+            ControlKind::Nrpn | ControlKind::Rpn => 0..=16383,
+        }
+    }
+}
+
+/// A specific MIDI controller that sends values
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ControlSource {
+    pub kind: ControlKind,
+    /// CC number (MSB number for 14-bit CCs) or (N)RPN parameter number
+    pub number: u16,
+}
+
+impl ControlSource {
+    pub fn new(kind: ControlKind, number: u16) -> Self {
+        Self { kind, number }
+    }
+
+    pub fn cc(cc: u7) -> Self {
+        Self::new(ControlKind::Cc, cc.as_int() as u16)
+    }
+}
+
+impl fmt::Display for ControlSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ControlKind::Cc => write!(f, "CC {}", self.number),
+            ControlKind::Cc14 => write!(f, "CC {}/{}", self.number, self.number + 32),
+            ControlKind::Nrpn => write!(f, "NRPN {}", self.number),
+            ControlKind::Rpn => write!(f, "RPN {}", self.number),
+        }
+    }
+}
+
+/// Maps a MIDI controller onto (part of) the range of a parameter
+#[derive(Clone, PartialEq)]
+pub struct CcMapping {
+    pub source: ControlSource,
+    pub param: ControlParam,
+
+    /// Normalized value [0,1] of the parameter when the CC is at its lowest
+    pub min: f64,
+
+    /// Normalized value [0,1] of the parameter when the CC is at its highest
+    pub max: f64,
+
+    /// Sweep the range from max to min instead
+    pub invert: bool,
+
+    /// How the CC value is shaped before it is mapped onto the range
+    pub curve: ResponseCurve,
+}
+
+impl CcMapping {
+    pub fn new(source: ControlSource, param: ControlParam) -> Self {
+        Self {
+            source,
+            param,
+            min: 0.0,
+            max: 1.0,
+            invert: false,
+            curve: ResponseCurve::Linear,
+        }
+    }
+
+    /// Whether values from a controller should move this mapping's parameter
+    pub fn responds_to(&self, source: &ControlSource) -> bool {
+        // the MSB of a 14-bit CC arrives as a plain CC before the LSB refines it
+        self.source == *source
+            || (self.source.kind == ControlKind::Cc14
+                && *source == ControlSource::new(ControlKind::Cc, self.source.number))
+    }
+
+    /// Normalized parameter value for a normalized controller value
+    pub fn map(&self, cc_value: f64) -> f64 {
+        let x = if self.invert {
+            1.0 - cc_value
+        } else {
+            cc_value
+        };
+        lerp(self.min..=self.max, self.curve.apply(x.clamp(0.0, 1.0)))
+    }
+
+    /// Normalized controller value that maps to a normalized parameter value, i.e. where the
+    /// controller should be to match the parameter
+    pub fn unmap(&self, param_value: f64) -> f64 {
+        let y = if self.max == self.min {
+            0.0
+        } else {
+            ((param_value - self.min) / (self.max - self.min)).clamp(0.0, 1.0)
+        };
+        let x = self.curve.invert(y);
+        if self.invert {
+            1.0 - x
+        } else {
+            x
+        }
+    }
+}
+
+#[derive(Clone, Copy, Display, VariantArray, PartialEq)]
+pub enum ResponseCurve {
+    Linear,
+    /// Slow at first, fast towards the top
+    Exponential,
+    /// Fast at first, slow towards the top
+    Logarithmic,
+    /// Slow at both ends, fast in the middle
+    #[strum(to_string = "S-curve")]
+    SCurve,
 }
 
-type MidiControlMap = Vec<(u7, ControlParam)>;
+impl ResponseCurve {
+    pub fn apply(&self, x: f64) -> f64 {
+        match self {
+            ResponseCurve::Linear => x,
+            ResponseCurve::Exponential => x * x,
+            ResponseCurve::Logarithmic => 1.0 - (1.0 - x) * (1.0 - x),
+            ResponseCurve::SCurve => x * x * (3.0 - 2.0 * x),
+        }
+    }
+
+    /// Input that `apply` turns into `y`
+    pub fn invert(&self, y: f64) -> f64 {
+        match self {
+            ResponseCurve::Linear => y,
+            ResponseCurve::Exponential => y.sqrt(),
+            ResponseCurve::Logarithmic => 1.0 - (1.0 - y).sqrt(),
+            ResponseCurve::SCurve => 0.5 - ((1.0 - 2.0 * y).asin() / 3.0).sin(),
+        }
+    }
+}
+
+/// Several mappings may share a CC so that one control can move many parameters
+pub type MidiControlMap = Vec<CcMapping>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, what: &str) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{what}: {actual} instead of {expected}"
+        );
+    }
+
+    #[test]
+    fn curves_invert_their_response() {
+        for curve in ResponseCurve::VARIANTS {
+            for i in 0..=20 {
+                let x = i as f64 / 20.0;
+                assert_close(curve.invert(curve.apply(x)), x, &curve.to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn unmap_finds_the_controller_value_that_maps_to_a_parameter_value() {
+        let source = ControlSource::new(ControlKind::Cc, 1);
+        for curve in ResponseCurve::VARIANTS {
+            for invert in [false, true] {
+                for (min, max) in [(0.0, 1.0), (0.2, 0.9), (0.8, 0.1)] {
+                    let mapping = CcMapping {
+                        min,
+                        max,
+                        invert,
+                        curve: *curve,
+                        ..CcMapping::new(source, ControlParam::Density)
+                    };
+                    for i in 0..=20 {
+                        let x = i as f64 / 20.0;
+                        let what = format!("{curve} from {min} to {max}, inverted: {invert}");
+                        assert_close(mapping.unmap(mapping.map(x)), x, &what);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unmap_clamps_values_outside_the_range() {
+        let mapping = CcMapping {
+            min: 0.2,
+            max: 0.6,
+            ..CcMapping::new(
+                ControlSource::new(ControlKind::Cc, 1),
+                ControlParam::Density,
+            )
+        };
+        assert_close(mapping.unmap(0.0), 0.0, "below");
+        assert_close(mapping.unmap(1.0), 1.0, "above");
+
+        let inverted = CcMapping {
+            invert: true,
+            ..mapping
+        };
+        assert_close(inverted.unmap(0.0), 1.0, "below, inverted");
+        assert_close(inverted.unmap(1.0), 0.0, "above, inverted");
+    }
+}
@@ -0,0 +1,28 @@
+//! Stored settings that MIDI program changes switch between. They are only kept in memory, as
+//! samples are stored with them.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{audio_clip::AudioClip, params::EmitterParams};
+
+/// Bank and program that a preset is stored under
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProgramNumber {
+    /// 14-bit bank number made of the bank select MSB (CC 0) and LSB (CC 32)
+    pub bank: u16,
+    pub program: u8,
+}
+
+impl fmt::Display for ProgramNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.bank, self.program)
+    }
+}
+
+pub struct Preset {
+    pub params: EmitterParams,
+    /// sample to switch to along with the settings, by name
+    pub sample: Option<(String, AudioClip<f32>)>,
+}
+
+pub type PresetBank = BTreeMap<ProgramNumber, Preset>;
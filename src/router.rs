@@ -0,0 +1,207 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{Receiver, Sender},
+    Arc,
+};
+
+use midly::{
+    live::{LiveEvent, SystemCommon, SystemRealtime},
+    num::{u4, u7},
+    MidiMessage,
+};
+
+use crate::{
+    emitter::{EmitterMessage, Expression},
+    midi::{self, ChannelMode, ControlDecoder, MidiOut},
+    params::ControlSource,
+    presets::ProgramNumber,
+    tempo::{ClockFollower, Transport},
+};
+
+/// MPE timbre dimension is sent as CC 74 on each member channel
+const MPE_TIMBRE_CC: u8 = 74;
+
+/// Bank select is sent as CC 0 (MSB) and CC 32 (LSB) ahead of a program change
+const BANK_SELECT_MSB: u8 = 0;
+const BANK_SELECT_LSB: u8 = 32;
+
+/// Changes made in the GUI that the MIDI thread needs to know about
+pub enum RouterUpdate {
+    /// A new emitter was created that should receive the notes from now on
+    Emitter(Sender<EmitterMessage>),
+    ChannelMode(ChannelMode),
+    /// Output that incoming notes should be echoed to, if any
+    Thru(Option<MidiOut>),
+}
+
+/// Lets the MIDI thread report controllers to the GUI while it waits for MIDI learn
+#[derive(Clone)]
+pub struct MidiLearnLink {
+    pub active: Arc<AtomicBool>,
+    pub sender: Sender<ControlSource>,
+}
+
+/// Turns incoming MIDI messages into emitter messages.
+/// Lives on the MIDI thread and only hears about GUI changes through `RouterUpdate`s, so it never
+/// waits on a lock held by the GUI.
+pub struct MidiRouter {
+    updates: Receiver<RouterUpdate>,
+    emitter: Option<Sender<EmitterMessage>>,
+    channel_mode: ChannelMode,
+    decoder: ControlDecoder,
+    learn: MidiLearnLink,
+    /// reports program changes to the GUI, which holds the presets
+    programs: Sender<ProgramNumber>,
+    /// bank selected on each channel
+    banks: [u16; 16],
+    thru: Option<MidiOut>,
+    clock: ClockFollower,
+    /// tempo that was last sent to the emitter
+    sent_bpm: f32,
+}
+
+impl MidiRouter {
+    pub fn new(
+        updates: Receiver<RouterUpdate>,
+        learn: MidiLearnLink,
+        programs: Sender<ProgramNumber>,
+    ) -> Self {
+        Self {
+            updates,
+            emitter: None,
+            channel_mode: ChannelMode::Omni,
+            decoder: ControlDecoder::default(),
+            learn,
+            programs,
+            banks: [0; 16],
+            thru: None,
+            clock: ClockFollower::new(),
+            sent_bpm: 0.0,
+        }
+    }
+
+    /// Handle an event received at `stamp` microseconds
+    pub fn handle(&mut self, stamp: u64, event: LiveEvent) {
+        while let Ok(update) = self.updates.try_recv() {
+            match update {
+                RouterUpdate::Emitter(sender) => self.emitter = Some(sender),
+                RouterUpdate::ChannelMode(mode) => self.channel_mode = mode,
+                RouterUpdate::Thru(output) => self.thru = output,
+            }
+        }
+
+        match event {
+            LiveEvent::Midi { channel, message } => self.handle_message(channel, message),
+            LiveEvent::Realtime(realtime) => self.handle_realtime(stamp, realtime),
+            LiveEvent::Common(SystemCommon::SysEx(data)) => self.handle_sysex(data),
+            // song position, time code and the like don't matter to the emitter
+            LiveEvent::Common(_) => {}
+        }
+    }
+
+    fn handle_sysex(&mut self, data: &[u7]) {
+        match midi::master_volume(data) {
+            Some(volume) => self.send(EmitterMessage::MasterVolume(volume)),
+            None => eprintln!("Skipping SysEx message of {} bytes", data.len()),
+        }
+    }
+
+    fn handle_realtime(&mut self, stamp: u64, realtime: SystemRealtime) {
+        let transport = match realtime {
+            SystemRealtime::TimingClock => {
+                if let Some(bpm) = self.clock.tick(stamp) {
+                    // don't flood the emitter with jitter in the clock's timing
+                    if (bpm - self.sent_bpm).abs() >= 0.05 {
+                        self.send(EmitterMessage::Tempo(bpm));
+                        self.sent_bpm = bpm;
+                    }
+                }
+                self.send(EmitterMessage::ClockTick);
+                return;
+            }
+            SystemRealtime::Start => Transport::Start,
+            SystemRealtime::Continue => Transport::Continue,
+            SystemRealtime::Stop => Transport::Stop,
+            _ => return,
+        };
+        self.send(EmitterMessage::Transport(transport));
+    }
+
+    fn handle_message(&mut self, channel: u4, message: MidiMessage) {
+        if !self.channel_mode.accepts(channel) {
+            return;
+        }
+
+        if self.channel_mode.is_expression_channel(channel) {
+            let expression = match message {
+                MidiMessage::PitchBend { bend } => Some(Expression::PitchBend(bend.as_f32())),
+                MidiMessage::ChannelAftertouch { vel } => {
+                    Some(Expression::Pressure(vel.as_int() as f32 / 127.0))
+                }
+                MidiMessage::Controller { controller, value }
+                    if controller.as_int() == MPE_TIMBRE_CC =>
+                {
+                    Some(Expression::Timbre(value.as_int() as f32 / 127.0))
+                }
+                _ => None,
+            };
+
+            if let Some(expression) = expression {
+                self.send(EmitterMessage::Expression {
+                    channel,
+                    expression,
+                });
+                return;
+            }
+        }
+
+        match message {
+            MidiMessage::NoteOn { key, vel } => {
+                self.echo([0x90 | channel.as_int(), key.as_int(), vel.as_int()]);
+                self.send(EmitterMessage::NoteOn { channel, key, vel });
+            }
+            MidiMessage::NoteOff { key, vel } => {
+                self.echo([0x80 | channel.as_int(), key.as_int(), vel.as_int()]);
+                self.send(EmitterMessage::NoteOff { channel, key });
+            }
+            MidiMessage::Controller { controller, value } => {
+                let bank = &mut self.banks[channel.as_int() as usize];
+                match controller.as_int() {
+                    BANK_SELECT_MSB => *bank = *bank & 0x7f | (value.as_int() as u16) << 7,
+                    BANK_SELECT_LSB => *bank = *bank & !0x7f | value.as_int() as u16,
+                    _ => {}
+                }
+
+                for control in self.decoder.decode(channel, controller, value) {
+                    if self.learn.active.load(Ordering::Relaxed) {
+                        let _ = self.learn.sender.send(control.source);
+                    }
+
+                    if let Some(emitter) = &self.emitter {
+                        let _ = emitter.send(EmitterMessage::Control(control));
+                    }
+                }
+            }
+            MidiMessage::ProgramChange { program } => {
+                let _ = self.programs.send(ProgramNumber {
+                    bank: self.banks[channel.as_int() as usize],
+                    program: program.as_int(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Pass a message on to the thru output
+    fn echo(&self, message: [u8; 3]) {
+        if let Some(thru) = &self.thru {
+            thru.send(&message);
+        }
+    }
+
+    fn send(&self, msg: EmitterMessage) {
+        if let Some(emitter) = &self.emitter {
+            let _ = emitter.send(msg);
+        }
+    }
+}
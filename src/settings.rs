@@ -0,0 +1,230 @@
+//! Settings that are remembered between launches, kept in a JSON file
+
+use std::{env, fs, path::PathBuf};
+
+use midly::num::u4;
+use serde_json::{Map, Value};
+
+use crate::midi::{ChannelMode, MpeZone, ZoneSide};
+
+#[derive(Clone, Default, PartialEq)]
+pub struct Settings {
+    /// chosen MIDI inputs by port name
+    pub midi_inputs: Vec<(String, ChannelMode)>,
+    /// port of the OSC server, if it should be running
+    pub osc_port: Option<u16>,
+    /// sound that `/dirt/play` events are played for, if not the default
+    pub dirt_sound: Option<String>,
+}
+
+impl Settings {
+    /// Read the settings file, using defaults for anything that's missing or unreadable
+    pub fn load() -> Settings {
+        match settings_path().and_then(|path| fs::read_to_string(path).ok()) {
+            Some(contents) => Settings::from_json(&contents),
+            None => Settings::default(),
+        }
+    }
+
+    pub fn save(&self) {
+        let Some(path) = settings_path() else {
+            return;
+        };
+
+        let contents = self.to_json();
+        let written = match path.parent() {
+            Some(dir) => fs::create_dir_all(dir).and_then(|_| fs::write(&path, contents)),
+            None => fs::write(&path, contents),
+        };
+        if let Err(err) = written {
+            eprintln!("Can't save settings to {}: {err}", path.display());
+        }
+    }
+
+    fn from_json(contents: &str) -> Settings {
+        let mut settings = Settings::default();
+        let object = match serde_json::from_str(contents) {
+            Ok(Value::Object(object)) => object,
+            Ok(_) => {
+                eprintln!("Skipping settings that aren't a JSON object");
+                return settings;
+            }
+            Err(err) => {
+                eprintln!("Skipping unreadable settings: {err}");
+                return settings;
+            }
+        };
+
+        for (name, value) in &object {
+            let parsed = match name.as_str() {
+                "midi_inputs" => value.as_array().map(|inputs| {
+                    for input in inputs {
+                        match parse_midi_input(input) {
+                            Some(input) => settings.midi_inputs.push(input),
+                            None => eprintln!("Skipping unknown MIDI input {input}"),
+                        }
+                    }
+                }),
+                "osc_port" => value
+                    .as_u64()
+                    .and_then(|port| u16::try_from(port).ok())
+                    .map(|port| settings.osc_port = Some(port)),
+                "dirt_sound" => value
+                    .as_str()
+                    .map(|sound| settings.dirt_sound = Some(sound.to_string())),
+                _ => None,
+            };
+            if parsed.is_none() {
+                eprintln!("Skipping unknown setting {name:?}: {value}");
+            }
+        }
+        settings
+    }
+
+    fn to_json(&self) -> String {
+        let inputs = self
+            .midi_inputs
+            .iter()
+            .map(|(port_name, mode)| {
+                let mut input = Map::new();
+                input.insert("port".to_string(), Value::from(port_name.as_str()));
+                input.extend(format_channel_mode(mode));
+                Value::Object(input)
+            })
+            .collect();
+
+        let mut object = Map::new();
+        object.insert("midi_inputs".to_string(), Value::Array(inputs));
+        if let Some(port) = self.osc_port {
+            object.insert("osc_port".to_string(), Value::from(port));
+        }
+        if let Some(sound) = &self.dirt_sound {
+            object.insert("dirt_sound".to_string(), Value::from(sound.as_str()));
+        }
+        format!("{:#}\n", Value::Object(object))
+    }
+}
+
+fn settings_path() -> Option<PathBuf> {
+    let config_dir = if cfg!(windows) {
+        PathBuf::from(env::var_os("APPDATA")?)
+    } else if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+        PathBuf::from(dir)
+    } else {
+        PathBuf::from(env::var_os("HOME")?).join(".config")
+    };
+    Some(config_dir.join("nebulizer").join("settings.json"))
+}
+
+fn parse_midi_input(input: &Value) -> Option<(String, ChannelMode)> {
+    let port_name = input.get("port")?.as_str()?;
+    let mode = parse_channel_mode(input)?;
+    Some((port_name.to_string(), mode))
+}
+
+/// `"mode"` is `"omni"`, `"single"` with a `"channel"` (0 to 15), or `"mpe"` with a `"zone"`
+/// (`"lower"` or `"upper"`) and the number of `"member_channels"` (1 to 15)
+fn format_channel_mode(mode: &ChannelMode) -> Map<String, Value> {
+    let mut fields = Map::new();
+    match mode {
+        ChannelMode::Omni => {
+            fields.insert("mode".to_string(), Value::from("omni"));
+        }
+        ChannelMode::Single(channel) => {
+            fields.insert("mode".to_string(), Value::from("single"));
+            fields.insert("channel".to_string(), Value::from(channel.as_int()));
+        }
+        ChannelMode::Mpe(zone) => {
+            let side = match zone.side {
+                ZoneSide::Lower => "lower",
+                ZoneSide::Upper => "upper",
+            };
+            fields.insert("mode".to_string(), Value::from("mpe"));
+            fields.insert("zone".to_string(), Value::from(side));
+            fields.insert(
+                "member_channels".to_string(),
+                Value::from(zone.member_channels),
+            );
+        }
+    }
+    fields
+}
+
+fn parse_channel_mode(fields: &Value) -> Option<ChannelMode> {
+    let number = |name: &str| -> Option<u8> { fields.get(name)?.as_u64()?.try_into().ok() };
+    let mode = match fields.get("mode")?.as_str()? {
+        "omni" => ChannelMode::Omni,
+        "single" => ChannelMode::Single(u4::try_from(number("channel")?)?),
+        "mpe" => {
+            let side = match fields.get("zone")?.as_str()? {
+                "lower" => ZoneSide::Lower,
+                "upper" => ZoneSide::Upper,
+                _ => return None,
+            };
+            let member_channels = number("member_channels")?;
+            if !(1..=15).contains(&member_channels) {
+                return None;
+            }
+            ChannelMode::Mpe(MpeZone {
+                side,
+                member_channels,
+            })
+        }
+        _ => return None,
+    };
+    Some(mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_survive_a_round_trip() {
+        let settings = Settings {
+            midi_inputs: vec![
+                ("Keystation\t24:0".to_string(), ChannelMode::Omni),
+                (
+                    "two\nlines \"quoted\"".to_string(),
+                    ChannelMode::Single(u4::from(15)),
+                ),
+                ("Lower".to_string(), ChannelMode::Mpe(MpeZone::default())),
+                (
+                    "Upper".to_string(),
+                    ChannelMode::Mpe(MpeZone {
+                        side: ZoneSide::Upper,
+                        member_channels: 1,
+                    }),
+                ),
+            ],
+            osc_port: Some(57120),
+            dirt_sound: Some("superpiano".to_string()),
+        };
+        assert!(Settings::from_json(&settings.to_json()) == settings);
+        assert!(Settings::from_json(&Settings::default().to_json()) == Settings::default());
+    }
+
+    #[test]
+    fn unreadable_settings_are_skipped() {
+        assert!(Settings::from_json("midi-input\tomni\tKeystation") == Settings::default());
+        assert!(Settings::from_json("[]") == Settings::default());
+
+        let settings = Settings::from_json(
+            r#"{
+                "midi_inputs": [
+                    {"port": "kept", "mode": "single", "channel": 3},
+                    {"port": "channel too high", "mode": "single", "channel": 16},
+                    {"port": "no members", "mode": "mpe", "zone": "lower", "member_channels": 0},
+                    {"port": "no zone", "mode": "mpe", "member_channels": 4},
+                    {"port": "unknown mode", "mode": "poly"},
+                    {"mode": "omni"}
+                ],
+                "osc_port": 70000,
+                "dirt_sound": 1
+            }"#,
+        );
+        assert!(settings.midi_inputs == [("kept".to_string(), ChannelMode::Single(u4::from(3)))]);
+        assert_eq!(settings.osc_port, None);
+        assert_eq!(settings.dirt_sound, None);
+    }
+}
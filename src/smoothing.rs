@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use strum_macros::{Display, VariantArray};
+
+#[derive(Clone, Copy, Display, VariantArray, PartialEq, Eq)]
+pub enum SmoothingMode {
+    /// Exponentially approach the new value
+    #[strum(to_string = "One-pole")]
+    OnePole,
+    /// Ramp to the new value at a constant rate
+    Linear,
+}
+
+/// Follows a target value gradually so that parameter changes don't cause clicks
+pub struct Smoother {
+    value: f32,
+    target: f32,
+    /// change per update while ramping linearly
+    step: f32,
+}
+
+impl Smoother {
+    pub fn new(value: f32) -> Self {
+        Self {
+            value,
+            target: value,
+            step: 0.0,
+        }
+    }
+
+    /// Move one update closer to the target and return the new value.
+    /// `updates` is the number of updates that the smoothing time spans.
+    pub fn next(&mut self, target: f32, mode: SmoothingMode, updates: f32) -> f32 {
+        if updates <= 1.0 {
+            self.value = target;
+            self.target = target;
+            return self.value;
+        }
+
+        match mode {
+            SmoothingMode::OnePole => {
+                let coefficient = 1.0 - (-1.0 / updates).exp();
+                self.value += (target - self.value) * coefficient;
+            }
+            SmoothingMode::Linear => {
+                if target != self.target || self.step == 0.0 {
+                    self.step = (target - self.value) / updates;
+                }
+
+                let remaining = target - self.value;
+                if remaining.abs() <= self.step.abs() {
+                    self.value = target;
+                } else {
+                    self.value += self.step;
+                }
+            }
+        }
+
+        self.target = target;
+        self.value
+    }
+
+    pub fn get(&self) -> f32 {
+        self.value
+    }
+}
+
+/// Number of updates at a given rate that a smoothing time spans
+pub fn smoothing_updates(time: Duration, updates_per_second: u32) -> f32 {
+    time.as_secs_f32() * updates_per_second as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Values of `steps` updates towards `target`, with a smoothing time of as many updates
+    fn ramp(smoother: &mut Smoother, target: f32, mode: SmoothingMode, steps: usize) -> Vec<f32> {
+        (0..steps)
+            .map(|_| smoother.next(target, mode, steps as f32))
+            .collect()
+    }
+
+    #[test]
+    fn one_pole_converges_without_overshooting() {
+        let mut smoother = Smoother::new(0.0);
+        let values = ramp(&mut smoother, 1.0, SmoothingMode::OnePole, 100);
+        // one time constant covers 1 - 1/e of the way
+        assert!((values[99] - (1.0 - (-1.0f32).exp())).abs() < 1e-4);
+        assert!(values.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // ten time constants are close enough
+        for _ in 0..900 {
+            assert!(smoother.next(1.0, SmoothingMode::OnePole, 100.0) <= 1.0);
+        }
+        assert!(1.0 - smoother.get() < 1e-4);
+    }
+
+    #[test]
+    fn linear_ramp_reaches_the_target_in_its_steps() {
+        for (start, target, steps) in [(0.0, 1.0, 10), (0.3, -0.7, 7), (20.0, 1000.0, 441)] {
+            let mut smoother = Smoother::new(start);
+            let values = ramp(&mut smoother, target, SmoothingMode::Linear, steps);
+            assert_eq!(values[steps - 1], target, "{start} to {target}");
+            assert!(values[..steps - 1].iter().all(|value| *value != target));
+            let (low, high) = (start.min(target), start.max(target));
+            assert!(values.iter().all(|value| (low..=high).contains(value)));
+
+            // and stays there
+            assert_eq!(
+                smoother.next(target, SmoothingMode::Linear, steps as f32),
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn linear_ramp_starts_over_from_where_it_was_retargeted() {
+        let mut smoother_halfway = Smoother::new(0.0);
+        for _ in 0..5 {
+            smoother_halfway.next(1.0, SmoothingMode::Linear, 10.0);
+        }
+        assert!((smoother_halfway.get() - 0.5).abs() < 1e-6);
+
+        // the new step covers the rest of the way in the full smoothing time
+        let values = ramp(&mut smoother_halfway, 0.0, SmoothingMode::Linear, 10);
+        assert!((values[0] - 0.45).abs() < 1e-6);
+        assert_eq!(values[9], 0.0);
+        assert!(values.iter().all(|value| (0.0..0.5).contains(value)));
+    }
+
+    #[test]
+    fn short_smoothing_jumps_to_the_target() {
+        let mut smoother = Smoother::new(0.0);
+        for mode in [SmoothingMode::OnePole, SmoothingMode::Linear] {
+            assert_eq!(smoother.next(0.5, mode, 1.0), 0.5);
+            assert_eq!(smoother.next(-0.5, mode, 0.0), -0.5);
+        }
+        assert_eq!(smoothing_updates(Duration::from_millis(10), 48000), 480.0);
+    }
+}
@@ -0,0 +1,287 @@
+use std::{collections::VecDeque, fmt, time::Duration};
+
+use strum_macros::{Display, VariantArray};
+
+/// Length of a note relative to a whole note
+#[derive(Clone, Copy, Display, VariantArray, PartialEq, Eq)]
+pub enum NoteValue {
+    #[strum(to_string = "1/1")]
+    Whole,
+    #[strum(to_string = "1/2")]
+    Half,
+    #[strum(to_string = "1/4")]
+    Quarter,
+    #[strum(to_string = "1/8")]
+    Eighth,
+    #[strum(to_string = "1/16")]
+    Sixteenth,
+    #[strum(to_string = "1/32")]
+    ThirtySecond,
+}
+
+#[derive(Clone, Copy, Display, VariantArray, PartialEq, Eq)]
+pub enum NoteModifier {
+    Straight,
+    /// One and a half times as long
+    Dotted,
+    /// Three in the time of two
+    Triplet,
+}
+
+/// Musical length that is converted to a duration using the current tempo
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct NoteDivision {
+    pub value: NoteValue,
+    pub modifier: NoteModifier,
+}
+
+impl NoteDivision {
+    pub fn new(value: NoteValue, modifier: NoteModifier) -> Self {
+        Self { value, modifier }
+    }
+
+    /// Length in quarter notes
+    pub fn beats(&self) -> f32 {
+        let beats = 4.0 / (1 << self.value as u32) as f32;
+        match self.modifier {
+            NoteModifier::Straight => beats,
+            NoteModifier::Dotted => beats * 1.5,
+            NoteModifier::Triplet => beats * 2.0 / 3.0,
+        }
+    }
+
+    pub fn duration(&self, bpm: f32) -> Duration {
+        Duration::from_secs_f32(self.beats() * 60.0 / bpm)
+    }
+}
+
+impl fmt::Display for NoteDivision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let modifier = match self.modifier {
+            NoteModifier::Straight => "",
+            NoteModifier::Dotted => ".",
+            NoteModifier::Triplet => "T",
+        };
+        write!(f, "{}{modifier}", self.value)
+    }
+}
+
+/// Factor for the time between an onset and the next one, delaying every second onset.
+/// A swing of 1 turns straight notes into a triplet shuffle.
+pub fn swing_scale(swing: f32, after_offbeat: bool) -> f32 {
+    if after_offbeat {
+        1.0 - swing / 3.0
+    } else {
+        1.0 + swing / 3.0
+    }
+}
+
+/// MIDI clock sends this many ticks per quarter note
+pub const CLOCKS_PER_BEAT: usize = 24;
+
+/// Ticks further apart than this (in microseconds) mean that the clock was stopped in between
+const MAX_TICK_GAP: u64 = 500_000;
+
+/// Derives the tempo from the spacing of incoming MIDI clock ticks
+pub struct ClockFollower {
+    /// timestamps of the most recent ticks in microseconds, spanning up to a beat
+    ticks: VecDeque<u64>,
+}
+
+impl ClockFollower {
+    pub fn new() -> Self {
+        Self {
+            ticks: VecDeque::with_capacity(CLOCKS_PER_BEAT + 1),
+        }
+    }
+
+    /// Register a clock tick and return the tempo averaged over the last beat, once known
+    pub fn tick(&mut self, stamp: u64) -> Option<f32> {
+        if let Some(last) = self.ticks.back() {
+            if stamp.saturating_sub(*last) > MAX_TICK_GAP {
+                self.ticks.clear();
+            }
+        }
+
+        if self.ticks.len() > CLOCKS_PER_BEAT {
+            self.ticks.pop_front();
+        }
+        self.ticks.push_back(stamp);
+
+        let span = self.ticks.back()? - self.ticks.front()?;
+        if span == 0 {
+            return None;
+        }
+        let beats = (self.ticks.len() - 1) as f32 / CLOCKS_PER_BEAT as f32;
+        Some(beats * 60_000_000.0 / span as f32)
+    }
+}
+
+/// Position in MIDI clock ticks, estimated for every frame in between the ticks that arrive
+pub struct ClockPosition {
+    /// ticks received, or the estimated position when a tick came after a gap
+    ticks: u64,
+    /// never more than a tick ahead of the received ones while the clock is running
+    position: f64,
+    /// position handed out by the latest `advance`
+    reported: f64,
+    /// `u64::MAX` until the first tick
+    frames_since_tick: u64,
+    /// frames without a tick after which the clock counts as stopped
+    max_gap_frames: u64,
+}
+
+impl ClockPosition {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            ticks: 0,
+            position: 0.0,
+            reported: 0.0,
+            frames_since_tick: u64::MAX,
+            max_gap_frames: sample_rate as u64 * MAX_TICK_GAP / 1_000_000,
+        }
+    }
+
+    /// Register a clock tick
+    pub fn tick(&mut self) {
+        // without ticks the position ran on at the tempo, so carry on from there
+        self.ticks = (self.ticks + 1).max(self.position as u64);
+        self.position = self.position.max(self.ticks as f64);
+        self.frames_since_tick = 0;
+    }
+
+    /// Move on by a frame at the given pace and return how many ticks passed since the previous
+    /// frame. Jumps ahead when a tick arrives early and waits for a tick that is late, so that
+    /// the position never drifts away from the clock.
+    pub fn advance(&mut self, ticks_per_frame: f64) -> f64 {
+        self.frames_since_tick = self.frames_since_tick.saturating_add(1);
+        self.position += ticks_per_frame;
+        if self.frames_since_tick <= self.max_gap_frames {
+            self.position = self.position.min(self.ticks as f64 + 1.0);
+        }
+        let elapsed = self.position - self.reported;
+        self.reported = self.position;
+        elapsed
+    }
+}
+
+#[derive(Clone, Copy, Display, VariantArray, PartialEq, Eq)]
+pub enum TempoSource {
+    /// Tempo set in nebulizer itself
+    Internal,
+    /// Tempo follows clock ticks from the connected MIDI inputs
+    #[strum(to_string = "MIDI clock")]
+    MidiClock,
+}
+
+/// MIDI realtime transport messages
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Start,
+    Stop,
+    Continue,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// Ticks the position moves on by over `frames` frames
+    fn advance(clock: &mut ClockPosition, ticks_per_frame: f64, frames: usize) -> f64 {
+        (0..frames).map(|_| clock.advance(ticks_per_frame)).sum()
+    }
+
+    fn assert_ticks(elapsed: f64, expected: f64) {
+        assert!(
+            (elapsed - expected).abs() < 1e-9,
+            "{elapsed} ticks, not {expected}"
+        );
+    }
+
+    /// Tempi that `follower` reports for `count` ticks at `interval` microseconds, starting at
+    /// `start`
+    fn follow(follower: &mut ClockFollower, start: u64, interval: u64, count: u64) -> Vec<f32> {
+        (0..count)
+            .filter_map(|i| follower.tick(start + i * interval))
+            .collect()
+    }
+
+    fn assert_bpm(bpm: f32, expected: f32) {
+        assert!((bpm - expected).abs() < 1e-3, "{bpm} BPM, not {expected}");
+    }
+
+    #[test]
+    fn follower_measures_the_tick_interval() {
+        let mut follower = ClockFollower::new();
+        // 20 ms per tick is 125 BPM
+        let tempi = follow(&mut follower, 1_000_000, 20_000, 3 * CLOCKS_PER_BEAT as u64);
+        // the first tick alone has no interval yet
+        assert_eq!(tempi.len(), 3 * CLOCKS_PER_BEAT - 1);
+        for bpm in tempi {
+            assert_bpm(bpm, 125.0);
+        }
+
+        // uneven ticks average out over a beat
+        let mut stamp = 1_000_000 + (3 * CLOCKS_PER_BEAT as u64 - 1) * 20_000;
+        let mut bpm = None;
+        for i in 0..2 * CLOCKS_PER_BEAT {
+            stamp += if i % 2 == 0 { 19_000 } else { 21_000 };
+            bpm = follower.tick(stamp);
+        }
+        assert_bpm(bpm.unwrap(), 125.0);
+    }
+
+    #[test]
+    fn follower_starts_over_after_a_gap() {
+        let mut follower = ClockFollower::new();
+        follow(&mut follower, 0, 20_000, CLOCKS_PER_BEAT as u64);
+
+        // the clock stopped and comes back at 100 BPM, which the old ticks don't slow down
+        let start = 23 * 20_000 + MAX_TICK_GAP + 1;
+        assert_eq!(follower.tick(start), None);
+        for bpm in follow(&mut follower, start + 25_000, 25_000, 5) {
+            assert_bpm(bpm, 100.0);
+        }
+    }
+
+    #[test]
+    fn position_runs_freely_without_a_clock() {
+        let mut clock = ClockPosition::new(SAMPLE_RATE);
+        assert_ticks(advance(&mut clock, 0.01, 1000), 10.0);
+    }
+
+    #[test]
+    fn position_waits_for_a_slower_clock() {
+        let mut clock = ClockPosition::new(SAMPLE_RATE);
+        clock.tick();
+        // the pace would be four ticks ahead by now, but the position waits at the next tick
+        assert_ticks(advance(&mut clock, 0.01, 400), 2.0);
+        clock.tick();
+        assert_ticks(advance(&mut clock, 0.01, 400), 1.0);
+    }
+
+    #[test]
+    fn position_jumps_to_a_faster_clock() {
+        let mut clock = ClockPosition::new(SAMPLE_RATE);
+        clock.tick();
+        assert_ticks(advance(&mut clock, 0.01, 50), 1.5);
+        clock.tick();
+        clock.tick();
+        // catches up on one and a half ticks, then moves on from the latest tick
+        assert_ticks(clock.advance(0.01), 1.51);
+    }
+
+    #[test]
+    fn position_carries_on_after_the_clock_stopped() {
+        let mut clock = ClockPosition::new(SAMPLE_RATE);
+        clock.tick();
+        // waits at the next tick for half a second, then runs freely for another half
+        assert_ticks(advance(&mut clock, 0.001, SAMPLE_RATE as usize), 26.0);
+
+        // a new tick doesn't set the position back
+        clock.tick();
+        assert_ticks(clock.advance(0.001), 0.001);
+    }
+}
@@ -1,11 +1,14 @@
 use std::f32::consts::{PI, TAU};
 
 use eframe::egui::{
-    lerp, remap_clamp, Color32, DragValue, Pos2, Response, Sense, Shape, Stroke, Ui, Vec2, Widget,
+    lerp, remap_clamp, DragValue, Pos2, Response, RichText, Sense, Shape, Stroke, Ui, Vec2, Widget,
     WidgetText,
 };
 
-use crate::{numeric::Numeric, params::Parameter};
+use crate::{
+    numeric::Numeric,
+    params::{ControlParam, MidiControlMap, Parameter},
+};
 
 const ARC_RESOLUTION: usize = 32;
 
@@ -14,15 +17,22 @@ pub struct ParameterKnob<'a, I> {
 
     diameter: f32,
     drag_speed: f64,
-    min_decimals: usize,
     max_decimals: Option<usize>,
     label: Option<WidgetText>,
     suffix: Option<String>,
-    fill: Option<Color32>,
+    midi_learn: Option<MidiLearn<'a>>,
 
     is_duration: bool,
 }
 
+/// Lets the knob's parameter be mapped to a MIDI CC by moving a hardware control
+struct MidiLearn<'a> {
+    param: ControlParam,
+    /// parameter currently waiting for a CC message, shared by all knobs
+    learning: &'a mut Option<ControlParam>,
+    cc_map: &'a mut MidiControlMap,
+}
+
 impl<'a, I> ParameterKnob<'a, I>
 where
     I: Numeric,
@@ -32,11 +42,10 @@ where
             param,
             diameter: 24.0,
             drag_speed: 0.002,
-            min_decimals: 0,
             max_decimals: None,
             label: None,
             suffix: None,
-            fill: None,
+            midi_learn: None,
             is_duration: false,
         };
 
@@ -51,12 +60,6 @@ where
         knob
     }
 
-    #[inline]
-    pub fn min_decimals(mut self, decimals: usize) -> Self {
-        self.min_decimals = decimals;
-        self
-    }
-
     #[inline]
     pub fn max_decimals(mut self, decimals: usize) -> Self {
         self.max_decimals = Some(decimals);
@@ -75,9 +78,19 @@ where
         self
     }
 
+    /// Enable learning and forgetting MIDI CC mappings from the knob's context menu
     #[inline]
-    pub fn fill(mut self, color: Color32) -> Self {
-        self.fill = Some(color);
+    pub fn midi_learn(
+        mut self,
+        param: ControlParam,
+        learning: &'a mut Option<ControlParam>,
+        cc_map: &'a mut MidiControlMap,
+    ) -> Self {
+        self.midi_learn = Some(MidiLearn {
+            param,
+            learning,
+            cc_map,
+        });
         self
     }
 }
@@ -95,7 +108,7 @@ where
     fn knob_ui(&mut self, ui: &Ui, response: &mut Response) {
         if response.dragged() {
             let drag_delta = response.drag_delta();
-            let delta = (self.drag_speed) * (drag_delta.x + drag_delta.y * -1.0) as f64;
+            let delta = (self.drag_speed) * (drag_delta.x - drag_delta.y) as f64;
 
             let norm_val = self.param.get_normalized();
             self.param
@@ -107,7 +120,11 @@ where
         if ui.is_rect_visible(rect) {
             let bg_color = ui.visuals().weak_text_color();
             let tick_color = ui.visuals().text_color();
-            let fill_color = self.fill.unwrap_or(ui.visuals().selection.bg_fill);
+            let fill_color = if self.is_learning() {
+                ui.visuals().warn_fg_color
+            } else {
+                ui.visuals().selection.bg_fill
+            };
 
             let rot_padding = 0.2;
             let radius = self.diameter / 2.0;
@@ -131,7 +148,7 @@ where
                 rect.center(),
                 radius,
                 Stroke::new(2.0, fill_color),
-                angle_range.start().clone(),
+                *angle_range.start(),
                 value_angle,
             );
 
@@ -145,6 +162,59 @@ where
             ui.painter().add(tick);
         }
     }
+
+    fn is_learning(&self) -> bool {
+        self.midi_learn
+            .as_ref()
+            .is_some_and(|learn| *learn.learning == Some(learn.param.clone()))
+    }
+
+    /// Context menu for learning and forgetting CC mappings
+    fn midi_learn_menu(&mut self, response: &Response) {
+        let is_learning = self.is_learning();
+        if let Some(learn) = &mut self.midi_learn {
+            response.context_menu(|ui| {
+                if is_learning {
+                    if ui.button("Cancel learn").clicked() {
+                        *learn.learning = None;
+                        ui.close_menu();
+                    }
+                } else if ui.button("Learn").clicked() {
+                    *learn.learning = Some(learn.param.clone());
+                    ui.close_menu();
+                }
+
+                let is_mapped = learn.cc_map.iter().any(|m| m.param == learn.param);
+                if ui
+                    .add_enabled(is_mapped, eframe::egui::Button::new("Forget"))
+                    .clicked()
+                {
+                    learn.cc_map.retain(|m| m.param != learn.param);
+                    ui.close_menu();
+                }
+            });
+        }
+    }
+
+    /// Short description of the CCs mapped to this knob
+    fn mapping_text(&self) -> Option<String> {
+        let learn = self.midi_learn.as_ref()?;
+        if self.is_learning() {
+            return Some("Learning...".to_string());
+        }
+
+        let ccs: Vec<String> = learn
+            .cc_map
+            .iter()
+            .filter(|m| m.param == learn.param)
+            .map(|m| m.source.to_string())
+            .collect();
+        if ccs.is_empty() {
+            None
+        } else {
+            Some(ccs.join(", "))
+        }
+    }
 }
 
 impl<'a, I> Widget for ParameterKnob<'a, I>
@@ -159,6 +229,7 @@ where
 
             let mut response = self.allocate_knob_space(ui);
             self.knob_ui(ui, &mut response);
+            self.midi_learn_menu(&response);
 
             let range = self.param.range();
             let range_f64 = range.start().to_f64()..=range.end().to_f64();
@@ -172,7 +243,6 @@ where
                 val_ref.to_f64()
             })
             .clamp_range(range_f64.clone())
-            .min_decimals(self.min_decimals)
             .max_decimals_opt(self.max_decimals)
             .speed(self.drag_speed * (range_f64.end() - range_f64.start()));
 
@@ -205,6 +275,10 @@ where
             if drag_r.changed() {
                 self.param.set(value);
             }
+
+            if let Some(text) = self.mapping_text() {
+                ui.label(RichText::new(text).small().weak());
+            }
         })
         .response
     }
@@ -33,7 +33,7 @@ impl WaveformData {
         let bin_size = clip.data.len() / WAVEFORM_RESOLUTION;
 
         let mut points: [(f32, f32); WAVEFORM_RESOLUTION] = [(0.0, 0.0); WAVEFORM_RESOLUTION];
-        for i in 0..WAVEFORM_RESOLUTION {
+        for (i, point) in points.iter_mut().enumerate() {
             let mut max = 0.0;
             let mut min = 0.0;
             for j in 0..bin_size {
@@ -45,7 +45,7 @@ impl WaveformData {
                     min = val;
                 }
             }
-            points[i] = (min, max);
+            *point = (min, max);
         }
 
         Self {